@@ -14,19 +14,30 @@
 
 //! Implementation of the AIDL interface of the Virt Manager.
 
+use crate::cgroup::ResourceUsage;
 use crate::config::VmConfig;
-use crate::crosvm::VmInstance;
+use crate::crosvm::{BalloonStats, CrashReport, VmInstance};
+use crate::snapshot::Snapshot;
 use crate::{Cid, FIRST_GUEST_CID};
+use android_system_virtmanager::aidl::android::system::virtmanager::ConsolePort::ConsolePort;
+use android_system_virtmanager::aidl::android::system::virtmanager::CrashReport::CrashReport as AidlCrashReport;
+use android_system_virtmanager::aidl::android::system::virtmanager::DeathReason::DeathReason;
 use android_system_virtmanager::aidl::android::system::virtmanager::IVirtManager::IVirtManager;
 use android_system_virtmanager::aidl::android::system::virtmanager::IVirtualMachine::{
     BnVirtualMachine, IVirtualMachine,
 };
 use android_system_virtmanager::aidl::android::system::virtmanager::IVirtualMachineCallback::IVirtualMachineCallback;
+use android_system_virtmanager::aidl::android::system::virtmanager::MemoryBalloonStats::MemoryBalloonStats;
+use android_system_virtmanager::aidl::android::system::virtmanager::ResourceUsage::ResourceUsage as AidlResourceUsage;
 use android_system_virtmanager::aidl::android::system::virtmanager::VirtualMachineDebugInfo::VirtualMachineDebugInfo;
+use android_system_virtmanager::aidl::android::system::virtmanager::VirtualMachineInfo::VirtualMachineInfo;
+use android_system_virtmanager::aidl::android::system::virtmanager::VirtualMachineLabel::VirtualMachineLabel;
 use android_system_virtmanager::binder::{
     self, BinderFeatures, Interface, ParcelFileDescriptor, StatusCode, Strong, ThreadState,
 };
 use log::{debug, error};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::fs::File;
 use std::sync::{Arc, Mutex, Weak};
 
@@ -36,6 +47,13 @@ pub const BINDER_SERVICE_IDENTIFIER: &str = "android.system.virtmanager";
 /// Only processes running with one of these UIDs are allowed to call debug methods.
 const DEBUG_ALLOWED_UIDS: [u32; 2] = [0, 2000];
 
+/// Processes running with one of these UIDs can list all VMs, rather than only those they started.
+const LIST_ALL_VMS_ALLOWED_UIDS: [u32; 2] = [0, 1000];
+
+/// Only processes running with one of these UIDs are allowed to raise the scheduling priority of
+/// VMs.
+const SCHEDULER_PRIORITY_ALLOWED_UIDS: [u32; 2] = [0, 1000];
+
 /// Implementation of `IVirtManager`, the entry point of the AIDL service.
 #[derive(Debug, Default)]
 pub struct VirtManager {
@@ -53,49 +71,68 @@ impl IVirtManager for VirtManager {
         config_fd: &ParcelFileDescriptor,
         log_fd: Option<&ParcelFileDescriptor>,
     ) -> binder::Result<Strong<dyn IVirtualMachine>> {
-        let state = &mut *self.state.lock().unwrap();
-        let log_fd = log_fd
-            .map(|fd| fd.as_ref().try_clone().map_err(|_| StatusCode::UNKNOWN_ERROR))
-            .transpose()?;
-        let requester_uid = ThreadState::get_calling_uid();
-        let requester_sid = ThreadState::with_calling_sid(|sid| {
-            if let Some(sid) = sid {
-                match sid.to_str() {
-                    Ok(sid) => Ok(sid.to_owned()),
-                    Err(e) => {
-                        error!("SID was not valid UTF-8: {:?}", e);
-                        Err(StatusCode::BAD_VALUE)
-                    }
-                }
-            } else {
-                error!("Missing SID on startVm");
-                Err(StatusCode::UNKNOWN_ERROR)
-            }
+        let config_file = config_fd.as_ref();
+        let config = VmConfig::load(config_file).map_err(|e| {
+            error!("Failed to load VM config from {:?}: {:?}", config_file, e);
+            StatusCode::BAD_VALUE
         })?;
-        let requester_debug_pid = ThreadState::get_calling_pid();
-        let cid = state.allocate_cid()?;
-        let instance = start_vm(
-            config_fd.as_ref(),
-            cid,
-            log_fd,
-            requester_uid,
-            requester_sid,
-            requester_debug_pid,
-        )?;
-        state.add_vm(Arc::downgrade(&instance));
-        Ok(VirtualMachine::create(instance))
+        self.start_vm(&config, log_fd, None)
     }
 
-    /// Get a list of all currently running VMs. This method is only intended for debug purposes,
-    /// and as such is only permitted from the shell user.
-    fn debugListVms(&self) -> binder::Result<Vec<VirtualMachineDebugInfo>> {
+    /// Restore a VM from a snapshot previously taken by the calling UID, with the CID it had when the
+    /// snapshot was taken.
+    ///
+    /// Returns a binder `IVirtualMachine` object referring to it, as a handle for the client.
+    fn restoreVm(
+        &self,
+        snapshot_name: &str,
+        log_fd: Option<&ParcelFileDescriptor>,
+    ) -> binder::Result<Strong<dyn IVirtualMachine>> {
+        let snapshot =
+            Snapshot::new(ThreadState::get_calling_uid(), snapshot_name).map_err(|e| {
+                error!("Invalid snapshot name {:?}: {:?}", snapshot_name, e);
+                StatusCode::BAD_VALUE
+            })?;
+        let config = snapshot.load_config().map_err(|e| {
+            error!("Failed to load config of snapshot {:?}: {:?}", snapshot, e);
+            StatusCode::BAD_VALUE
+        })?;
+        self.start_vm(&config, log_fd, Some(&snapshot))
+    }
+
+    /// Get a list of the currently running VMs which have all of the given labels. Callers which
+    /// may not list all VMs only get those which they started.
+    fn listVms(&self, filter: &[VirtualMachineLabel]) -> binder::Result<Vec<VirtualMachineInfo>> {
+        let uid = ThreadState::get_calling_uid();
+        let list_all = LIST_ALL_VMS_ALLOWED_UIDS.contains(&uid);
+        let state = &*self.state.lock().unwrap();
+        let vms = state
+            .vms_with_labels(filter)
+            .into_iter()
+            .filter(|vm| list_all || vm.requester_uid == uid)
+            .map(|vm| VirtualMachineInfo {
+                cid: vm.cid as i32,
+                requesterUid: vm.requester_uid as i32,
+                running: vm.running(),
+                labels: to_parcelable_labels(&vm.labels),
+            })
+            .collect();
+        Ok(vms)
+    }
+
+    /// Get a list of all currently running VMs which have all of the given labels. This method is
+    /// only intended for debug purposes, and as such is only permitted from the shell user.
+    fn debugListVms(
+        &self,
+        filter: &[VirtualMachineLabel],
+    ) -> binder::Result<Vec<VirtualMachineDebugInfo>> {
         if !debug_access_allowed() {
             return Err(StatusCode::PERMISSION_DENIED.into());
         }
 
-        let state = &mut *self.state.lock().unwrap();
-        let vms = state.vms();
-        let cids = vms
+        let state = &*self.state.lock().unwrap();
+        let cids = state
+            .vms_with_labels(filter)
             .into_iter()
             .map(|vm| VirtualMachineDebugInfo {
                 cid: vm.cid as i32,
@@ -103,6 +140,8 @@ impl IVirtManager for VirtManager {
                 requesterSid: vm.requester_sid.clone(),
                 requesterPid: vm.requester_debug_pid,
                 running: vm.running(),
+                hugepages: vm.hugepages(),
+                labels: to_parcelable_labels(&vm.labels),
             })
             .collect();
         Ok(cids)
@@ -133,6 +172,77 @@ impl IVirtManager for VirtManager {
     }
 }
 
+impl VirtManager {
+    /// Start a new VM with the given configuration, optionally restoring it from a snapshot, and
+    /// assign it the next available CID.
+    fn start_vm(
+        &self,
+        config: &VmConfig,
+        log_fd: Option<&ParcelFileDescriptor>,
+        restore_from: Option<&Snapshot>,
+    ) -> binder::Result<Strong<dyn IVirtualMachine>> {
+        if config.gdb_port.is_some() && !debug_access_allowed() {
+            error!("Only callers allowed to use debug methods may enable the GDB stub");
+            return Err(StatusCode::PERMISSION_DENIED.into());
+        }
+        if config.scheduler_priority.is_some()
+            && !SCHEDULER_PRIORITY_ALLOWED_UIDS.contains(&ThreadState::get_calling_uid())
+        {
+            error!("Only privileged callers may raise the scheduling priority of a VM");
+            return Err(StatusCode::PERMISSION_DENIED.into());
+        }
+
+        let state = &mut *self.state.lock().unwrap();
+        let log_fd = clone_file(log_fd)?;
+        let requester_uid = ThreadState::get_calling_uid();
+        let requester_sid = ThreadState::with_calling_sid(|sid| {
+            if let Some(sid) = sid {
+                match sid.to_str() {
+                    Ok(sid) => Ok(sid.to_owned()),
+                    Err(e) => {
+                        error!("SID was not valid UTF-8: {:?}", e);
+                        Err(StatusCode::BAD_VALUE)
+                    }
+                }
+            } else {
+                error!("Missing SID on startVm or restoreVm");
+                Err(StatusCode::UNKNOWN_ERROR)
+            }
+        })?;
+        let requester_debug_pid = ThreadState::get_calling_pid();
+        let cid = match restore_from {
+            // The guest's vsock state refers to its CID, so it must be restored with the same one.
+            Some(snapshot) => {
+                let cid = snapshot.load_cid().map_err(|e| {
+                    error!("Failed to load CID of snapshot {:?}: {:?}", snapshot, e);
+                    StatusCode::BAD_VALUE
+                })?;
+                if cid < FIRST_GUEST_CID || state.cid_in_use(cid) {
+                    error!("CID {} of snapshot {:?} is not available", cid, snapshot);
+                    return Err(StatusCode::ALREADY_EXISTS.into());
+                }
+                cid
+            }
+            None => state.allocate_cid()?,
+        };
+        let instance = VmInstance::start(
+            config,
+            cid,
+            log_fd,
+            requester_uid,
+            requester_sid,
+            requester_debug_pid,
+            restore_from,
+        )
+        .map_err(|e| {
+            error!("Failed to start VM with config {:?}: {:?}", config, e);
+            StatusCode::UNKNOWN_ERROR
+        })?;
+        state.add_vm(Arc::downgrade(&instance));
+        Ok(VirtualMachine::create(instance))
+    }
+}
+
 /// Check whether the caller of the current Binder method is allowed to call debug methods.
 fn debug_access_allowed() -> bool {
     let uid = ThreadState::get_calling_uid();
@@ -140,6 +250,48 @@ fn debug_access_allowed() -> bool {
     DEBUG_ALLOWED_UIDS.contains(&uid)
 }
 
+/// Duplicate the file descriptor of the given `ParcelFileDescriptor`, if any, so that it can be kept
+/// after the Binder call returns.
+fn clone_file(fd: Option<&ParcelFileDescriptor>) -> binder::Result<Option<File>> {
+    fd.map(|fd| fd.as_ref().try_clone().map_err(|_| StatusCode::UNKNOWN_ERROR.into())).transpose()
+}
+
+/// Convert the labels of a VM to AIDL parcelables.
+fn to_parcelable_labels(labels: &BTreeMap<String, String>) -> Vec<VirtualMachineLabel> {
+    labels
+        .iter()
+        .map(|(key, value)| VirtualMachineLabel { key: key.clone(), value: value.clone() })
+        .collect()
+}
+
+/// Convert balloon statistics reported by crosvm to the AIDL parcelable, using -1 for statistics
+/// which the guest didn't report.
+fn to_parcelable_balloon_stats(stats: &BalloonStats) -> MemoryBalloonStats {
+    let value = |stat: Option<u64>| stat.map_or(-1, |v| v as i64);
+    MemoryBalloonStats {
+        balloonActualBytes: stats.balloon_actual as i64,
+        swapIn: value(stats.swap_in),
+        swapOut: value(stats.swap_out),
+        majorFaults: value(stats.major_faults),
+        minorFaults: value(stats.minor_faults),
+        freeMemoryBytes: value(stats.free_memory),
+        totalMemoryBytes: value(stats.total_memory),
+        availableMemoryBytes: value(stats.available_memory),
+        diskCachesBytes: value(stats.disk_caches),
+    }
+}
+
+/// Convert the resource usage of a VM to the AIDL parcelable, using -1 for the peak memory usage if
+/// it is not known.
+fn to_parcelable_resource_usage(usage: &ResourceUsage) -> AidlResourceUsage {
+    AidlResourceUsage {
+        cpuTimeMicros: usage.cpu_time_us as i64,
+        peakMemoryBytes: usage.peak_memory_bytes.map_or(-1, |v| v as i64),
+        ioReadBytes: usage.io_read_bytes as i64,
+        ioWriteBytes: usage.io_write_bytes as i64,
+    }
+}
+
 /// Implementation of the AIDL `IVirtualMachine` interface. Used as a handle to a VM.
 #[derive(Debug)]
 struct VirtualMachine {
@@ -164,6 +316,50 @@ impl IVirtualMachine for VirtualMachine {
         Ok(self.instance.running())
     }
 
+    fn getResourceUsage(&self) -> binder::Result<AidlResourceUsage> {
+        let usage = self.instance.resource_usage().map_err(|e| {
+            error!("Failed to get resource usage of VM {}: {:?}", self.instance.cid, e);
+            StatusCode::UNKNOWN_ERROR
+        })?;
+        Ok(to_parcelable_resource_usage(&usage))
+    }
+
+    fn getTapInterfaceName(&self) -> binder::Result<Option<String>> {
+        Ok(self.instance.tap_interface_name().map(ToOwned::to_owned))
+    }
+
+    fn getNetworkNamespace(&self) -> binder::Result<Option<ParcelFileDescriptor>> {
+        self.instance
+            .network_namespace()
+            .map(|netns| {
+                netns.try_clone().map(ParcelFileDescriptor::new).map_err(|e| {
+                    error!("Failed to clone network namespace file: {}", e);
+                    StatusCode::UNKNOWN_ERROR.into()
+                })
+            })
+            .transpose()
+    }
+
+    fn getConsolePorts(&self) -> binder::Result<Vec<ConsolePort>> {
+        self.instance
+            .console_ports()
+            .iter()
+            .map(|port| {
+                let clone = |file: &File| {
+                    file.try_clone().map(ParcelFileDescriptor::new).map_err(|e| {
+                        error!("Failed to clone console port {} file: {}", port.name, e);
+                        StatusCode::UNKNOWN_ERROR
+                    })
+                };
+                Ok(ConsolePort {
+                    name: port.name.clone(),
+                    output: Some(clone(&port.client_out)?),
+                    input: Some(clone(&port.client_in)?),
+                })
+            })
+            .collect()
+    }
+
     fn registerCallback(
         &self,
         callback: &Strong<dyn IVirtualMachineCallback>,
@@ -172,6 +368,120 @@ impl IVirtualMachine for VirtualMachine {
         self.instance.callbacks.add(callback.clone());
         Ok(())
     }
+
+    fn setConsoleFds(
+        &self,
+        console_out_fd: Option<&ParcelFileDescriptor>,
+        console_in_fd: Option<&ParcelFileDescriptor>,
+        log_fd: Option<&ParcelFileDescriptor>,
+    ) -> binder::Result<()> {
+        let console_out_fd = clone_file(console_out_fd)?;
+        let console_in_fd = clone_file(console_in_fd)?;
+        let log_fd = clone_file(log_fd)?;
+        self.instance.set_console_fds(console_out_fd, console_in_fd, log_fd).map_err(|e| {
+            error!("Failed to resume VM {} for new console: {:?}", self.instance.cid, e);
+            StatusCode::UNKNOWN_ERROR
+        })?;
+        Ok(())
+    }
+
+    fn setMemoryBalloon(&self, num_bytes: i64) -> binder::Result<()> {
+        if num_bytes < 0 {
+            return Err(StatusCode::BAD_VALUE.into());
+        }
+        self.instance.set_memory_balloon(num_bytes as u64).map_err(|e| {
+            error!("Failed to set memory balloon of VM {}: {:?}", self.instance.cid, e);
+            StatusCode::UNKNOWN_ERROR
+        })?;
+        Ok(())
+    }
+
+    fn setMemoryMib(&self, memory_mib: i32) -> binder::Result<()> {
+        let memory_mib = u32::try_from(memory_mib).map_err(|_| StatusCode::BAD_VALUE)?;
+        self.instance.set_memory_mib(memory_mib).map_err(|e| {
+            error!(
+                "Failed to set memory of VM {} to {} MiB: {:?}",
+                self.instance.cid, memory_mib, e
+            );
+            StatusCode::UNKNOWN_ERROR
+        })?;
+        Ok(())
+    }
+
+    fn getMemoryBalloonStats(&self) -> binder::Result<MemoryBalloonStats> {
+        let stats = self.instance.memory_balloon_stats().map_err(|e| {
+            error!("Failed to get memory balloon stats of VM {}: {:?}", self.instance.cid, e);
+            StatusCode::UNKNOWN_ERROR
+        })?;
+        Ok(to_parcelable_balloon_stats(&stats))
+    }
+
+    fn resizeDisk(&self, index: i32, new_size: i64) -> binder::Result<()> {
+        let index = usize::try_from(index).map_err(|_| StatusCode::BAD_VALUE)?;
+        let new_size = u64::try_from(new_size).map_err(|_| StatusCode::BAD_VALUE)?;
+        self.instance.resize_disk(index, new_size).map_err(|e| {
+            error!("Failed to resize disk {} of VM {}: {:?}", index, self.instance.cid, e);
+            StatusCode::UNKNOWN_ERROR
+        })?;
+        Ok(())
+    }
+
+    fn setVmmSwapEnabled(&self, enabled: bool) -> binder::Result<()> {
+        self.instance.set_swap_enabled(enabled).map_err(|e| {
+            error!("Failed to set vmm-swap of VM {} to {}: {:?}", self.instance.cid, enabled, e);
+            StatusCode::UNKNOWN_ERROR
+        })?;
+        Ok(())
+    }
+
+    fn trimVmmSwap(&self) -> binder::Result<()> {
+        self.instance.trim_swap().map_err(|e| {
+            error!("Failed to trim vmm-swap of VM {}: {:?}", self.instance.cid, e);
+            StatusCode::UNKNOWN_ERROR
+        })?;
+        Ok(())
+    }
+
+    fn attachUsbDevice(&self, usb_device_fd: &ParcelFileDescriptor) -> binder::Result<i32> {
+        let device = usb_device_fd.as_ref().try_clone().map_err(|e| {
+            error!("Failed to clone USB device fd: {}", e);
+            StatusCode::UNKNOWN_ERROR
+        })?;
+        let port = self.instance.attach_usb_device(device).map_err(|e| {
+            error!("Failed to attach USB device to VM {}: {:?}", self.instance.cid, e);
+            StatusCode::UNKNOWN_ERROR
+        })?;
+        Ok(port.into())
+    }
+
+    fn detachUsbDevice(&self, port: i32) -> binder::Result<()> {
+        let port = u8::try_from(port).map_err(|_| StatusCode::BAD_VALUE)?;
+        self.instance.detach_usb_device(port).map_err(|e| {
+            error!("Failed to detach USB device from VM {}: {:?}", self.instance.cid, e);
+            StatusCode::UNKNOWN_ERROR
+        })?;
+        Ok(())
+    }
+
+    fn snapshot(&self, name: &str) -> binder::Result<()> {
+        let snapshot = Snapshot::new(ThreadState::get_calling_uid(), name).map_err(|e| {
+            error!("Invalid snapshot name {:?}: {:?}", name, e);
+            StatusCode::BAD_VALUE
+        })?;
+        self.instance.snapshot(&snapshot).map_err(|e| {
+            error!("Failed to snapshot VM {} to {:?}: {:?}", self.instance.cid, snapshot, e);
+            StatusCode::UNKNOWN_ERROR
+        })?;
+        Ok(())
+    }
+
+    fn resume(&self) -> binder::Result<()> {
+        self.instance.wake().map_err(|e| {
+            error!("Failed to resume VM {}: {:?}", self.instance.cid, e);
+            StatusCode::UNKNOWN_ERROR
+        })?;
+        Ok(())
+    }
 }
 
 impl Drop for VirtualMachine {
@@ -188,10 +498,38 @@ pub struct VirtualMachineCallbacks(Mutex<Vec<Strong<dyn IVirtualMachineCallback>
 
 impl VirtualMachineCallbacks {
     /// Call all registered callbacks to say that the VM has died.
-    pub fn callback_on_died(&self, cid: Cid) {
+    pub fn callback_on_died(&self, cid: Cid, reason: DeathReason, usage: Option<&ResourceUsage>) {
+        let usage = usage.map(to_parcelable_resource_usage);
         let callbacks = &*self.0.lock().unwrap();
         for callback in callbacks {
-            if let Err(e) = callback.onDied(cid as i32) {
+            if let Err(e) = callback.onDied(cid as i32, reason, usage.as_ref()) {
+                error!("Error calling callback: {}", e);
+            }
+        }
+    }
+
+    /// Call all registered callbacks to say that the guest kernel has panicked, with the panic
+    /// message if it could be found in the console output.
+    pub fn callback_on_guest_panic(&self, cid: Cid, message: Option<String>) {
+        let callbacks = &*self.0.lock().unwrap();
+        for callback in callbacks {
+            if let Err(e) = callback.onGuestPanic(cid as i32, message.as_deref()) {
+                error!("Error calling callback: {}", e);
+            }
+        }
+    }
+
+    /// Call all registered callbacks to say that crosvm exited abnormally.
+    pub fn callback_on_crash(&self, cid: Cid, report: &CrashReport) {
+        let report = AidlCrashReport {
+            exitStatus: report.exit_status.unwrap_or(-1),
+            signal: report.signal.unwrap_or(-1),
+            crosvmStderr: report.crosvm_stderr.clone(),
+            consoleTail: report.console_tail.clone(),
+        };
+        let callbacks = &*self.0.lock().unwrap();
+        for callback in callbacks {
+            if let Err(e) = callback.onCrash(cid as i32, &report) {
                 error!("Error calling callback: {}", e);
             }
         }
@@ -227,6 +565,15 @@ impl State {
         self.vms.iter().filter_map(Weak::upgrade).collect()
     }
 
+    /// Get a list of VMs which still have Binder references to them and have all of the given
+    /// labels.
+    fn vms_with_labels(&self, filter: &[VirtualMachineLabel]) -> Vec<Arc<VmInstance>> {
+        let has_labels = |vm: &Arc<VmInstance>| {
+            filter.iter().all(|label| vm.labels.get(&label.key) == Some(&label.value))
+        };
+        self.vms().into_iter().filter(has_labels).collect()
+    }
+
     /// Add a new VM to the list.
     fn add_vm(&mut self, vm: Weak<VmInstance>) {
         // Garbage collect any entries from the stored list which no longer exist.
@@ -247,12 +594,22 @@ impl State {
         Some(self.debug_held_vms.swap_remove(pos))
     }
 
+    /// Return whether the given CID is used by a VM which still has Binder references to it.
+    fn cid_in_use(&self, cid: Cid) -> bool {
+        self.vms().iter().any(|vm| vm.cid == cid)
+    }
+
     /// Get the next available CID, or an error if we have run out.
     fn allocate_cid(&mut self) -> binder::Result<Cid> {
         // TODO(qwandor): keep track of which CIDs are currently in use so that we can reuse them.
-        let cid = self.next_cid;
-        self.next_cid = self.next_cid.checked_add(1).ok_or(StatusCode::UNKNOWN_ERROR)?;
-        Ok(cid)
+        loop {
+            let cid = self.next_cid;
+            self.next_cid = self.next_cid.checked_add(1).ok_or(StatusCode::UNKNOWN_ERROR)?;
+            // Skip any CIDs kept by VMs restored from snapshots.
+            if !self.cid_in_use(cid) {
+                return Ok(cid);
+            }
+        }
     }
 }
 
@@ -261,24 +618,3 @@ impl Default for State {
         State { next_cid: FIRST_GUEST_CID, vms: vec![], debug_held_vms: vec![] }
     }
 }
-
-/// Start a new VM instance from the given VM config file. This assumes the VM is not already
-/// running.
-fn start_vm(
-    config_file: &File,
-    cid: Cid,
-    log_fd: Option<File>,
-    requester_uid: u32,
-    requester_sid: String,
-    requester_debug_pid: i32,
-) -> binder::Result<Arc<VmInstance>> {
-    let config = VmConfig::load(config_file).map_err(|e| {
-        error!("Failed to load VM config from {:?}: {:?}", config_file, e);
-        StatusCode::BAD_VALUE
-    })?;
-    Ok(VmInstance::start(&config, cid, log_fd, requester_uid, requester_sid, requester_debug_pid)
-        .map_err(|e| {
-        error!("Failed to start VM from {:?}: {:?}", config_file, e);
-        StatusCode::UNKNOWN_ERROR
-    })?)
-}
@@ -0,0 +1,307 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Placement of crosvm processes in cgroups, and accounting of the resources they use.
+
+use crate::config::VmPriority;
+use crate::Cid;
+use anyhow::{bail, Context, Error};
+use log::warn;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The root of the cpuset hierarchy.
+const CPUSET_ROOT_DIR: &str = "/dev/cpuset";
+
+/// The cpuset under which Virt Manager creates a cpuset for each VM. This is created by
+/// `init_cpuset`.
+const VIRTMANAGER_CPUSET_DIR: &str = "/dev/cpuset/virtmanager";
+
+/// The cgroup v2 group under which Virt Manager creates a cgroup for each VM. This is created by
+/// `init`, with the cpu, memory and io controllers enabled for its children.
+const VIRTMANAGER_CGROUP_DIR: &str = "/sys/fs/cgroup/virtmanager";
+
+/// The controllers enabled for the children of the Virt Manager cgroup and each priority class
+/// group.
+const SUBTREE_CONTROLLERS: &str = "+cpu +memory +io";
+
+/// A cgroup v2 group for a single VM, used to account for the resources used by its crosvm process.
+/// The cgroup is removed when this is dropped.
+#[derive(Debug)]
+pub struct VmCgroup {
+    dir: PathBuf,
+}
+
+/// The resources used by a VM, summed over all its crosvm processes.
+#[derive(Clone, Debug, Default)]
+pub struct ResourceUsage {
+    /// The CPU time used, in microseconds.
+    pub cpu_time_us: u64,
+    /// The peak memory usage, in bytes, if the kernel reports it.
+    pub peak_memory_bytes: Option<u64>,
+    /// The number of bytes read from block devices.
+    pub io_read_bytes: u64,
+    /// The number of bytes written to block devices.
+    pub io_write_bytes: u64,
+}
+
+/// Create the cgroup under which Virt Manager creates a cgroup for each VM, if it doesn't already
+/// exist, and enable the controllers needed to account for their resources. Also set up a group
+/// for each priority class within it, as far as the kernel supports.
+pub fn init() -> Result<(), Error> {
+    let dir = Path::new(VIRTMANAGER_CGROUP_DIR);
+    create_dir_if_missing(dir)?;
+    write_cgroup_file(dir, "cgroup.subtree_control", SUBTREE_CONTROLLERS)?;
+    for &priority in &[VmPriority::Foreground, VmPriority::Background, VmPriority::Idle] {
+        if let Err(e) = init_priority_group(priority) {
+            warn!(
+                "Failed to set up cgroup for {:?} VMs, only adjusting OOM score: {:?}",
+                priority, e
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Create the cpuset under which Virt Manager creates a cpuset for each VM, if it doesn't already
+/// exist, allowing all the CPUs and memory nodes of the root cpuset.
+pub fn init_cpuset() -> Result<(), Error> {
+    let dir = Path::new(VIRTMANAGER_CPUSET_DIR);
+    create_dir_if_missing(dir)?;
+    for filename in &["cpus", "mems"] {
+        let path = Path::new(CPUSET_ROOT_DIR).join(filename);
+        let value =
+            fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+        write_cgroup_file(dir, filename, value.trim())?;
+    }
+    Ok(())
+}
+
+impl VmCgroup {
+    /// Create a cgroup for the VM with the given CID, within the group for its priority class if
+    /// there is one.
+    pub fn create(cid: Cid, priority: VmPriority) -> Result<VmCgroup, Error> {
+        let mut dir = priority_group_dir(priority);
+        if !dir.is_dir() {
+            dir = PathBuf::from(VIRTMANAGER_CGROUP_DIR);
+        }
+        let dir = dir.join(format!("vm-{}", cid));
+        // Remove any stale cgroup left behind by a previous VM with the same CID.
+        if dir.exists() {
+            fs::remove_dir(&dir).with_context(|| format!("Failed to remove stale {:?}", dir))?;
+        }
+        fs::create_dir(&dir).with_context(|| format!("Failed to create {:?}", dir))?;
+        Ok(VmCgroup { dir })
+    }
+
+    /// Move the process with the given PID, including all its threads, into the cgroup.
+    pub fn add_process(&self, pid: u32) -> Result<(), Error> {
+        write_cgroup_file(&self.dir, "cgroup.procs", &pid.to_string())
+    }
+
+    /// Open the file listing the processes in the cgroup for writing, so that a child process can
+    /// join the cgroup itself by writing 0 to it before it execs.
+    pub fn open_procs(&self) -> Result<File, Error> {
+        let path = self.dir.join("cgroup.procs");
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open {:?}", path))
+    }
+
+    /// Return the resources used by all processes which have been in the cgroup. The peak memory
+    /// usage is missing if the kernel doesn't track it for cgroups, which is the case before Linux
+    /// 5.19.
+    pub fn resource_usage(&self) -> Result<ResourceUsage, Error> {
+        let cpu_time_us = self.cpu_time_us()?;
+        let (io_read_bytes, io_write_bytes) = parse_io_stat(&self.read("io.stat")?)?;
+        let peak_memory_bytes = ["memory.peak", "memory.max_usage_in_bytes"]
+            .iter()
+            .find_map(|filename| self.read(filename).ok()?.trim().parse().ok());
+        Ok(ResourceUsage { cpu_time_us, peak_memory_bytes, io_read_bytes, io_write_bytes })
+    }
+
+    /// Return the CPU time used by all processes which have been in the cgroup, in microseconds.
+    pub fn cpu_time_us(&self) -> Result<u64, Error> {
+        parse_cpu_stat(&self.read("cpu.stat")?)
+    }
+
+    fn read(&self, filename: &str) -> Result<String, Error> {
+        let path = self.dir.join(filename);
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))
+    }
+}
+
+impl Drop for VmCgroup {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir(&self.dir) {
+            warn!("Failed to remove cgroup {:?}: {}", self.dir, e);
+        }
+    }
+}
+
+/// A cpuset cgroup for a single VM, restricting its crosvm process to a set of host CPUs. The
+/// cgroup is removed when this is dropped.
+#[derive(Debug)]
+pub struct CpuSet {
+    dir: PathBuf,
+}
+
+impl CpuSet {
+    /// Create a cpuset for the VM with the given CID, allowing only the given host CPUs.
+    pub fn create(cid: Cid, cpus: &[u32]) -> Result<CpuSet, Error> {
+        let parent = Path::new(VIRTMANAGER_CPUSET_DIR);
+        let dir = parent.join(format!("vm-{}", cid));
+        // Remove any stale cpuset left behind by a previous VM with the same CID.
+        if dir.exists() {
+            fs::remove_dir(&dir).with_context(|| format!("Failed to remove stale {:?}", dir))?;
+        }
+        fs::create_dir(&dir).with_context(|| format!("Failed to create {:?}", dir))?;
+        let cpuset = CpuSet { dir };
+
+        let cpus = cpus.iter().map(u32::to_string).collect::<Vec<_>>().join(",");
+        write_cgroup_file(&cpuset.dir, "cpus", &cpus)?;
+        // A cpuset can't have tasks until its memory nodes are set, so inherit them from the parent.
+        let mems = fs::read_to_string(parent.join("mems"))
+            .with_context(|| format!("Failed to read mems of {:?}", parent))?;
+        write_cgroup_file(&cpuset.dir, "mems", mems.trim())?;
+        Ok(cpuset)
+    }
+
+    /// Move the process with the given PID, including all its threads, into the cpuset.
+    pub fn add_process(&self, pid: u32) -> Result<(), Error> {
+        write_cgroup_file(&self.dir, "cgroup.procs", &pid.to_string())
+    }
+}
+
+impl Drop for CpuSet {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_dir(&self.dir) {
+            warn!("Failed to remove cpuset {:?}: {}", self.dir, e);
+        }
+    }
+}
+
+/// Return the directory of the cgroup for all VMs of the given priority class.
+fn priority_group_dir(priority: VmPriority) -> PathBuf {
+    let name = match priority {
+        VmPriority::Foreground => "foreground",
+        VmPriority::Background => "background",
+        VmPriority::Idle => "idle",
+    };
+    Path::new(VIRTMANAGER_CGROUP_DIR).join(name)
+}
+
+/// Create and configure the cgroup for all VMs of the given priority class. `cpu.idle` only exists
+/// from Linux 5.15, so this fails for the idle class on older kernels, though the group is still
+/// created.
+fn init_priority_group(priority: VmPriority) -> Result<(), Error> {
+    let settings: &[_] = match priority {
+        VmPriority::Foreground => &[("cpu.weight", "100")],
+        VmPriority::Background => &[("cpu.weight", "10")],
+        // An idle cgroup only gets CPU time when nothing else wants it.
+        VmPriority::Idle => &[("cpu.idle", "1")],
+    };
+    let dir = priority_group_dir(priority);
+    create_dir_if_missing(&dir)?;
+    write_cgroup_file(&dir, "cgroup.subtree_control", SUBTREE_CONTROLLERS)?;
+    for (filename, value) in settings {
+        write_cgroup_file(&dir, filename, value)?;
+    }
+    Ok(())
+}
+
+/// Return the total CPU time in microseconds from the contents of a cgroup's `cpu.stat` file.
+fn parse_cpu_stat(cpu_stat: &str) -> Result<u64, Error> {
+    cpu_stat
+        .lines()
+        .find_map(|line| line.strip_prefix("usage_usec "))
+        .and_then(|value| value.trim().parse().ok())
+        .context("Failed to find usage_usec in cpu.stat")
+}
+
+/// Return the total numbers of bytes read and written, summed over all block devices, from the
+/// contents of a cgroup's `io.stat` file.
+fn parse_io_stat(io_stat: &str) -> Result<(u64, u64), Error> {
+    let (mut read_bytes, mut write_bytes) = (0, 0);
+    // Each line of io.stat has a device number followed by key=value pairs.
+    for field in io_stat.split_whitespace() {
+        let mut parts = field.splitn(2, '=');
+        let total = match parts.next() {
+            Some("rbytes") => &mut read_bytes,
+            Some("wbytes") => &mut write_bytes,
+            _ => continue,
+        };
+        match parts.next().and_then(|value| value.parse::<u64>().ok()) {
+            Some(value) => *total += value,
+            None => bail!("Invalid io.stat field {:?}", field),
+        }
+    }
+    Ok((read_bytes, write_bytes))
+}
+
+/// Create the cgroup with the given directory, unless it already exists.
+fn create_dir_if_missing(dir: &Path) -> Result<(), Error> {
+    match fs::create_dir(dir) {
+        Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
+            Err(e).with_context(|| format!("Failed to create {:?}", dir))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Write the given value to a control file of the cgroup with the given directory.
+fn write_cgroup_file(dir: &Path, filename: &str, value: &str) -> Result<(), Error> {
+    let path = dir.join(filename);
+    fs::write(&path, value).with_context(|| format!("Failed to write {:?} to {:?}", value, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_stat() {
+        let cpu_stat = "usage_usec 1234567\nuser_usec 1000000\nsystem_usec 234567\n\
+            nr_periods 0\nnr_throttled 0\nthrottled_usec 0\n";
+        assert_eq!(parse_cpu_stat(cpu_stat).unwrap(), 1234567);
+    }
+
+    #[test]
+    fn test_parse_cpu_stat_missing_usage() {
+        assert!(parse_cpu_stat("").is_err());
+        assert!(parse_cpu_stat("user_usec 1000000\nsystem_usec 234567\n").is_err());
+        assert!(parse_cpu_stat("usage_usec lots\n").is_err());
+    }
+
+    #[test]
+    fn test_parse_io_stat() {
+        let io_stat = "254:0 rbytes=4096 wbytes=8192 rios=1 wios=2 dbytes=0 dios=0\n\
+            7:1 rbytes=1024 wbytes=0 rios=1 wios=0 dbytes=0 dios=0\n";
+        assert_eq!(parse_io_stat(io_stat).unwrap(), (5120, 8192));
+    }
+
+    #[test]
+    fn test_parse_io_stat_empty() {
+        // io.stat has no lines until the cgroup has done some I/O.
+        assert_eq!(parse_io_stat("").unwrap(), (0, 0));
+    }
+
+    #[test]
+    fn test_parse_io_stat_invalid() {
+        assert!(parse_io_stat("254:0 rbytes=many wbytes=0\n").is_err());
+        assert!(parse_io_stat("254:0 rbytes wbytes=0\n").is_err());
+    }
+}
@@ -16,12 +16,15 @@
 
 use anyhow::{bail, Error};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::BufReader;
 
 /// Configuration for a particular VM to be started.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct VmConfig {
+    /// A name for the VM, used to identify it in the system log.
+    pub name: Option<String>,
     /// The filename of the kernel image, if any.
     pub kernel: Option<String>,
     /// The filename of the initial ramdisk for the kernel, if any.
@@ -35,6 +38,63 @@ pub struct VmConfig {
     /// Disk images to be made available to the VM.
     #[serde(default)]
     pub disks: Vec<DiskImage>,
+    /// Whether to back the guest memory with transparent hugepages, if they are available on the
+    /// host. This reduces stage 2 TLB misses for memory-intensive guests.
+    #[serde(default)]
+    pub use_hugepages: bool,
+    /// Free-form key/value labels describing the VM, e.g. its role. These are not passed to the VM,
+    /// but can be used to find it when listing VMs.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// The amount of memory the guest starts with, in MiB, if not the crosvm default.
+    pub memory_mib: Option<u32>,
+    /// The maximum amount of memory the guest can be given while running, in MiB. If this is set
+    /// then the VM is created with this much memory, and all of it beyond `memory_mib` is held by
+    /// the memory balloon until it is released by `IVirtualMachine.setMemoryMib`. This requires
+    /// `memory_mib` and `balloon` to be set.
+    pub max_memory_mib: Option<u32>,
+    /// Whether to give the VM a virtio-balloon device, so that the host can reclaim memory from it.
+    /// Defaults to true, as crosvm gives every VM a balloon unless told otherwise.
+    #[serde(default = "default_balloon")]
+    pub balloon: bool,
+    /// Whether to restart the VM when the guest reboots, keeping its CID, console and Binder
+    /// handle, rather than treating the reboot as the VM dying. Connections to the VM are not kept.
+    #[serde(default)]
+    pub warm_reboot: bool,
+    /// The port on which crosvm should listen for a GDB client to debug the guest kernel, if any.
+    /// This is only allowed for VMs started by callers which may use debug methods.
+    pub gdb_port: Option<u16>,
+    /// Whether to give the VM a virtio-net device, backed by a TAP device which Virt Manager creates
+    /// in a network namespace of its own.
+    #[serde(default)]
+    pub network_supported: bool,
+    /// The virtio-snd device to give the VM, if any.
+    pub audio_config: Option<AudioConfig>,
+    /// The names of extra virtio-console ports to give the VM, in addition to its main console.
+    /// Clients can read and write each port through its own pair of file descriptors.
+    #[serde(default)]
+    pub console_ports: Vec<String>,
+    /// The host CPUs on which the VM's vCPUs may run. If this is empty they may run on any CPU.
+    #[serde(default)]
+    pub cpu_affinity: Vec<u32>,
+    /// The scheduling priority to give the VM's vCPU threads, if they should have more than the
+    /// normal priority. This is only allowed for privileged callers.
+    pub scheduler_priority: Option<SchedulerPriority>,
+    /// Whether crosvm should be able to swap the VM's memory out to disk with vmm-swap while it is
+    /// dormant. Swapping is only started when requested by the client.
+    #[serde(default)]
+    pub vmm_swap: bool,
+    /// Images to be made available to the VM as virtio-pmem devices. These are mapped directly into
+    /// guest memory, so the guest can access them with DAX rather than through its page cache.
+    #[serde(default)]
+    pub pmem_disks: Vec<DiskImage>,
+    /// The priority class of the VM, which determines how it competes with the rest of the system
+    /// for CPU and memory. Defaults to foreground.
+    pub vm_priority: Option<VmPriority>,
+    /// If set, the VM is suspended after there has been no activity on its console or from its
+    /// clients for this many seconds, and resumed as soon as there is. vsock connections don't
+    /// count, so clients should call `IVirtualMachine.resume` before connecting.
+    pub idle_suspend_timeout_secs: Option<u64>,
 }
 
 impl VmConfig {
@@ -47,6 +107,39 @@ impl VmConfig {
         if self.bootloader.is_some() && (self.kernel.is_some() || self.initrd.is_some()) {
             bail!("Can't have both bootloader and kernel/initrd image.");
         }
+        if self.gdb_port == Some(0) {
+            bail!("GDB port must not be 0.");
+        }
+        if let Some(audio_config) = &self.audio_config {
+            if !audio_config.use_speaker && !audio_config.use_microphone {
+                bail!("Audio config must enable at least one of the speaker and microphone.");
+            }
+        }
+        if self.memory_mib == Some(0) {
+            bail!("Memory size must not be 0.");
+        }
+        if let Some(max_memory_mib) = self.max_memory_mib {
+            match self.memory_mib {
+                Some(memory_mib) if memory_mib <= max_memory_mib => {}
+                Some(_) => bail!("Memory size must not be more than maximum memory size."),
+                None => bail!("Maximum memory size requires memory size to be set."),
+            }
+            if !self.balloon {
+                bail!("Maximum memory size requires a memory balloon.");
+            }
+        }
+        if self.idle_suspend_timeout_secs == Some(0) {
+            bail!("Idle suspend timeout must not be 0.");
+        }
+        let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+        for (i, name) in self.console_ports.iter().enumerate() {
+            if name.is_empty() || !name.chars().all(valid_char) {
+                bail!("Invalid console port name {:?}.", name);
+            }
+            if self.console_ports[..i].contains(name) {
+                bail!("Duplicate console port name {:?}.", name);
+            }
+        }
         Ok(())
     }
 
@@ -57,6 +150,11 @@ impl VmConfig {
     }
 }
 
+/// The value of `VmConfig::balloon` when the config doesn't specify it.
+fn default_balloon() -> bool {
+    true
+}
+
 /// A disk image to be made available to the VM.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct DiskImage {
@@ -65,3 +163,113 @@ pub struct DiskImage {
     /// Whether this disk should be writable by the VM.
     pub writable: bool,
 }
+
+/// A priority class for a VM as a whole.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VmPriority {
+    /// The VM competes for CPU and memory on equal terms with foreground apps.
+    Foreground,
+    /// The VM gets a small share of CPU time when there is contention. When memory is low it is
+    /// killed along with the previous app, after cached apps but before services.
+    Background,
+    /// The VM only gets CPU time when the system is otherwise idle. When memory is low it is killed
+    /// along with the most recently used cached apps, after most other cached apps.
+    Idle,
+}
+
+/// A raised scheduling priority for the vCPU threads of a VM.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SchedulerPriority {
+    /// Normal scheduling policy, but with a raised minimum utilization clamp so that the vCPUs are
+    /// placed on faster cores and run at a higher frequency.
+    Boosted,
+    /// The `SCHED_FIFO` real-time scheduling policy, with the maximum minimum utilization clamp.
+    /// This is for latency-critical VMs such as security VMs.
+    RealTime,
+}
+
+/// Configuration for the virtio-snd device of a VM. Audio is played and recorded through the
+/// Android audio framework, rather than by giving crosvm access to host audio devices.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct AudioConfig {
+    /// Whether the VM can play audio.
+    #[serde(default)]
+    pub use_speaker: bool,
+    /// Whether the VM can record audio.
+    #[serde(default)]
+    pub use_microphone: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(json: &str) -> VmConfig {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_minimal_config() {
+        let config = config(r#"{"kernel": "/data/local/tmp/kernel"}"#);
+        assert!(config.balloon);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn test_boot_image() {
+        assert!(config("{}").validate().is_err());
+        assert!(config(r#"{"bootloader": "bios"}"#).validate().is_ok());
+        assert!(config(r#"{"bootloader": "bios", "kernel": "kernel"}"#).validate().is_err());
+        assert!(config(r#"{"bootloader": "bios", "initrd": "initrd"}"#).validate().is_err());
+    }
+
+    #[test]
+    fn test_gdb_port() {
+        assert!(config(r#"{"kernel": "kernel", "gdb_port": 1234}"#).validate().is_ok());
+        assert!(config(r#"{"kernel": "kernel", "gdb_port": 0}"#).validate().is_err());
+    }
+
+    #[test]
+    fn test_audio_config() {
+        let speaker = r#"{"kernel": "kernel", "audio_config": {"use_speaker": true}}"#;
+        assert!(config(speaker).validate().is_ok());
+        let neither = r#"{"kernel": "kernel", "audio_config": {}}"#;
+        assert!(config(neither).validate().is_err());
+    }
+
+    #[test]
+    fn test_memory() {
+        assert!(config(r#"{"kernel": "kernel", "memory_mib": 0}"#).validate().is_err());
+        let valid = r#"{"kernel": "kernel", "memory_mib": 512, "max_memory_mib": 1024}"#;
+        assert!(config(valid).validate().is_ok());
+        let more_than_max = r#"{"kernel": "kernel", "memory_mib": 2048, "max_memory_mib": 1024}"#;
+        assert!(config(more_than_max).validate().is_err());
+        let max_only = r#"{"kernel": "kernel", "max_memory_mib": 1024}"#;
+        assert!(config(max_only).validate().is_err());
+        let no_balloon =
+            r#"{"kernel": "kernel", "memory_mib": 512, "max_memory_mib": 1024, "balloon": false}"#;
+        assert!(config(no_balloon).validate().is_err());
+    }
+
+    #[test]
+    fn test_idle_suspend_timeout() {
+        let valid = r#"{"kernel": "kernel", "idle_suspend_timeout_secs": 60}"#;
+        assert!(config(valid).validate().is_ok());
+        let zero = r#"{"kernel": "kernel", "idle_suspend_timeout_secs": 0}"#;
+        assert!(config(zero).validate().is_err());
+    }
+
+    #[test]
+    fn test_console_port_names() {
+        let valid = r#"{"kernel": "kernel", "console_ports": ["adb", "log-1.0_x"]}"#;
+        assert!(config(valid).validate().is_ok());
+        let empty = r#"{"kernel": "kernel", "console_ports": [""]}"#;
+        assert!(config(empty).validate().is_err());
+        let invalid = r#"{"kernel": "kernel", "console_ports": ["a,b"]}"#;
+        assert!(config(invalid).validate().is_err());
+        let duplicate = r#"{"kernel": "kernel", "console_ports": ["adb", "log", "adb"]}"#;
+        assert!(config(duplicate).validate().is_err());
+    }
+}
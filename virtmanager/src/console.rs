@@ -0,0 +1,621 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Forwarding of console and log streams between `crosvm` and the files provided by clients, which
+//! may be attached or replaced at any time while the VM is running.
+
+use log::{error, info, warn};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// The number of bytes of the most recent output of each stream which is kept for diagnostics.
+const TAIL_SIZE: usize = 16 * 1024;
+
+/// The maximum length of a line of console output written to the system log. Longer lines are split.
+const CONSOLE_LOG_MAX_LINE_LENGTH: usize = 1024;
+
+/// The number of console lines per second which each VM may write to the system log on average.
+const CONSOLE_LOG_LINES_PER_SEC: f64 = 20.0;
+
+/// The number of console lines which each VM may write to the system log in a burst.
+const CONSOLE_LOG_BURST_LINES: f64 = 200.0;
+
+/// How long the input forwarding thread waits for data before checking whether its source has been
+/// replaced, in milliseconds.
+const INPUT_POLL_TIMEOUT_MS: i32 = 100;
+
+/// How long to wait for a client to read VM output before detaching its file, in milliseconds.
+const OUTPUT_WRITE_TIMEOUT_MS: i32 = 1000;
+
+/// The console and log streams of a VM, forwarded between `crosvm` and files provided by the client.
+#[derive(Debug)]
+pub struct Console {
+    /// Where the output of the VM's console is sent.
+    console_out: Arc<OutputSink>,
+    /// Where input for the VM's console comes from.
+    console_in: InputSource,
+    /// Where the log output of `crosvm` itself is sent.
+    log: Arc<OutputSink>,
+    /// When the console was last used.
+    activity: Arc<Activity>,
+}
+
+/// The time of the most recent activity on a VM's console, in either direction.
+#[derive(Debug)]
+struct Activity(Mutex<Instant>);
+
+impl Default for Activity {
+    fn default() -> Self {
+        Activity(Mutex::new(Instant::now()))
+    }
+}
+
+impl Activity {
+    fn record(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    fn idle_duration(&self) -> Duration {
+        self.0.lock().unwrap().elapsed()
+    }
+}
+
+/// The ends of the pipes which should be passed to `crosvm` as its standard streams.
+#[derive(Debug)]
+pub struct ChildStdio {
+    pub stdin: File,
+    pub stdout: File,
+    pub stderr: File,
+}
+
+/// An extra virtio-console port of a VM, connected to pipes between `crosvm` and clients.
+#[derive(Debug)]
+pub struct ConsolePort {
+    /// The name of the port.
+    pub name: String,
+    /// The end of the pipe from which `crosvm` reads input for the guest.
+    pub guest_in: File,
+    /// The end of the pipe to which `crosvm` writes output from the guest.
+    pub guest_out: File,
+    /// The end of the pipe to which clients write input for the guest.
+    pub client_in: File,
+    /// The end of the pipe from which clients read output from the guest.
+    pub client_out: File,
+}
+
+impl ConsolePort {
+    /// Create the pipes for a new console port with the given name.
+    pub fn new(name: String) -> io::Result<ConsolePort> {
+        let (guest_in, client_in) = pipe()?;
+        let (client_out, guest_out) = pipe()?;
+        Ok(ConsolePort { name, guest_in, guest_out, client_in, client_out })
+    }
+}
+
+impl Console {
+    /// Create the pipes for the standard streams of `crosvm` and start forwarding the console
+    /// output to `console_out_fd`, if any, and to the system log tagged with `log_name`. Returns the
+    /// ends of the pipes to pass to `crosvm`.
+    pub fn new(
+        console_out_fd: Option<File>,
+        log_name: String,
+    ) -> io::Result<(Console, ChildStdio)> {
+        let console_out = OutputSink {
+            logger: Some(Mutex::new(ConsoleLogger::new(log_name))),
+            ..OutputSink::new(console_out_fd)
+        };
+        let console = Console {
+            console_out: Arc::new(console_out),
+            console_in: InputSource::default(),
+            log: Default::default(),
+            activity: Default::default(),
+        };
+        let stdio = console.connect()?;
+        Ok((console, stdio))
+    }
+
+    /// Create new pipes for the standard streams of a new `crosvm` process, and forward them to and
+    /// from the files currently attached. Returns the ends of the pipes to pass to `crosvm`.
+    ///
+    /// This should only be called once any previous `crosvm` process has exited and
+    /// `wait_for_eof` has returned.
+    pub fn connect(&self) -> io::Result<ChildStdio> {
+        let (stdin, console_in_write) = pipe()?;
+        let (console_out_read, stdout) = pipe()?;
+        let (log_read, stderr) = pipe()?;
+
+        self.console_out.forward_from(console_out_read, Some(self.activity.clone()));
+        self.log.forward_from(log_read, None);
+        self.console_in.set_destination(console_in_write);
+
+        Ok(ChildStdio { stdin, stdout, stderr })
+    }
+
+    /// Attach or replace the files used for the VM's console and `crosvm`'s log. `None` detaches
+    /// the corresponding stream.
+    pub fn set_fds(
+        &self,
+        console_out_fd: Option<File>,
+        console_in_fd: Option<File>,
+        log_fd: Option<File>,
+    ) {
+        self.console_out.replace(console_out_fd);
+        self.console_in.replace(console_in_fd, &self.activity);
+        self.log.replace(log_fd);
+    }
+
+    /// Record that the VM is in use, as if there had been activity on its console.
+    pub fn record_activity(&self) {
+        self.activity.record();
+    }
+
+    /// Return how long it has been since there was any activity on the console.
+    pub fn idle_duration(&self) -> Duration {
+        self.activity.idle_duration()
+    }
+
+    /// Wait until `crosvm` has closed its output streams and everything it wrote has been forwarded.
+    /// This should be called after `crosvm` has exited.
+    pub fn wait_for_eof(&self) {
+        self.console_out.wait_for_eof();
+        self.log.wait_for_eof();
+    }
+
+    /// Return the most recent output of the VM's console.
+    pub fn console_tail(&self) -> String {
+        self.console_out.tail()
+    }
+
+    /// Return the most recent log output of `crosvm`.
+    pub fn log_tail(&self) -> String {
+        self.log.tail()
+    }
+}
+
+impl Drop for Console {
+    fn drop(&mut self) {
+        // The input forwarding thread doesn't hold a reference to the console, so would otherwise
+        // keep the client's file and the pipe to `crosvm` open.
+        self.console_in.close();
+    }
+}
+
+/// A destination for an output stream of the VM, which may be replaced at any time.
+#[derive(Debug, Default)]
+struct OutputSink {
+    /// The file to which output is currently written, if any. This is only locked to take a
+    /// reference, so that a slow client can't block replacing the file.
+    file: Mutex<Option<Arc<File>>>,
+    /// The most recent output, kept for diagnostics even if no file is attached.
+    tail: Mutex<VecDeque<u8>>,
+    /// The thread forwarding output to this sink, if it has been started.
+    forwarder: Mutex<Option<JoinHandle<()>>>,
+    /// Where output is logged to the system log, if it should be.
+    logger: Option<Mutex<ConsoleLogger>>,
+}
+
+impl OutputSink {
+    /// Create a new sink which initially writes to the given file, if any.
+    fn new(file: Option<File>) -> OutputSink {
+        OutputSink { file: Mutex::new(file.map(Arc::new)), ..Default::default() }
+    }
+
+    /// Replace the file to which output is written. The previous file is closed once any write to
+    /// it which is in progress has finished. If `file` is `None` then further output is discarded.
+    fn replace(&self, file: Option<File>) {
+        let old = std::mem::replace(&mut *self.file.lock().unwrap(), file.map(Arc::new));
+        // The old file is released here, after the lock has been released.
+        drop(old);
+    }
+
+    /// Detach the given file, unless it has already been replaced.
+    fn detach(&self, file: &Arc<File>) {
+        let current = &mut *self.file.lock().unwrap();
+        if matches!(current, Some(current_file) if Arc::ptr_eq(current_file, file)) {
+            *current = None;
+        }
+    }
+
+    /// Write the given data to the current file, if any. If writing fails or the client doesn't
+    /// read it in time then the file is detached, as the client has most likely closed its end or
+    /// stopped reading.
+    fn write(&self, data: &[u8]) {
+        if let Some(logger) = &self.logger {
+            let logger = &mut *logger.lock().unwrap();
+            let entries = logger.write(data, Instant::now());
+            logger.log(entries);
+        }
+
+        let tail = &mut *self.tail.lock().unwrap();
+        tail.extend(data);
+        let excess = tail.len().saturating_sub(TAIL_SIZE);
+        tail.drain(..excess);
+
+        let file = self.file.lock().unwrap().clone();
+        if let Some(file) = file {
+            if let Err(e) = write_with_timeout(&file, data, OUTPUT_WRITE_TIMEOUT_MS) {
+                warn!("Error writing VM output, detaching file: {}", e);
+                self.detach(&file);
+            }
+        }
+    }
+
+    /// Handle the end of the current source, logging any incomplete last line.
+    fn finish(&self) {
+        if let Some(logger) = &self.logger {
+            let logger = &mut *logger.lock().unwrap();
+            let entries = logger.flush(Instant::now());
+            logger.log(entries);
+        }
+    }
+
+    /// Return the most recent output, lossily converted to UTF-8.
+    fn tail(&self) -> String {
+        let tail = &mut *self.tail.lock().unwrap();
+        String::from_utf8_lossy(tail.make_contiguous()).into_owned()
+    }
+
+    /// Spawn a thread which copies everything from `source` to this sink until `source` reaches
+    /// EOF, recording any output in `activity` if given.
+    fn forward_from(
+        self: &Arc<Self>,
+        mut source: impl Read + Send + 'static,
+        activity: Option<Arc<Activity>>,
+    ) {
+        let sink = self.clone();
+        let forwarder = thread::spawn(move || {
+            let mut buffer = [0; 1024];
+            loop {
+                match source.read(&mut buffer) {
+                    Ok(0) => {
+                        sink.finish();
+                        break;
+                    }
+                    Ok(size) => {
+                        if let Some(activity) = &activity {
+                            activity.record();
+                        }
+                        sink.write(&buffer[..size]);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        error!("Error reading VM output: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+        *self.forwarder.lock().unwrap() = Some(forwarder);
+    }
+
+    /// Wait until the forwarding thread has reached EOF on its source.
+    fn wait_for_eof(&self) {
+        if let Some(forwarder) = self.forwarder.lock().unwrap().take() {
+            if forwarder.join().is_err() {
+                error!("VM output forwarding thread panicked");
+            }
+        }
+    }
+}
+
+/// Splits the output of a VM's console into lines and writes them to the system log, tagged with the
+/// name and CID of the VM. Lines are dropped if the VM produces them faster than the rate limit, so
+/// that a guest can't flood the log.
+#[derive(Debug)]
+struct ConsoleLogger {
+    /// The name by which the VM is identified in the log.
+    name: String,
+    /// Output since the last complete line.
+    line: Vec<u8>,
+    /// The number of lines which may currently be logged, replenished over time up to the burst
+    /// limit.
+    allowance: f64,
+    /// When `allowance` was last replenished.
+    last_replenished: Instant,
+    /// The number of lines dropped since a line was last logged.
+    dropped: u64,
+}
+
+impl ConsoleLogger {
+    fn new(name: String) -> ConsoleLogger {
+        ConsoleLogger {
+            name,
+            line: vec![],
+            allowance: CONSOLE_LOG_BURST_LINES,
+            last_replenished: Instant::now(),
+            dropped: 0,
+        }
+    }
+
+    /// Return the entries to log for any complete lines in the given output, which arrived at
+    /// `now`, keeping the rest until more output arrives.
+    fn write(&mut self, data: &[u8], now: Instant) -> Vec<ConsoleLogEntry> {
+        let mut entries = vec![];
+        for &byte in data {
+            if byte == b'\n' {
+                self.end_line(now, &mut entries);
+            } else {
+                self.line.push(byte);
+                if self.line.len() >= CONSOLE_LOG_MAX_LINE_LENGTH {
+                    self.end_line(now, &mut entries);
+                }
+            }
+        }
+        entries
+    }
+
+    /// Return the entries to log for the current incomplete line, if any, at the end of the output.
+    fn flush(&mut self, now: Instant) -> Vec<ConsoleLogEntry> {
+        let mut entries = vec![];
+        self.end_line(now, &mut entries);
+        entries
+    }
+
+    /// Add the current line to `entries`, if it is not empty and the rate limit allows at `now`.
+    fn end_line(&mut self, now: Instant, entries: &mut Vec<ConsoleLogEntry>) {
+        if self.line.is_empty() {
+            return;
+        }
+        let elapsed = now.saturating_duration_since(self.last_replenished).as_secs_f64();
+        self.allowance =
+            (self.allowance + elapsed * CONSOLE_LOG_LINES_PER_SEC).min(CONSOLE_LOG_BURST_LINES);
+        self.last_replenished = now;
+
+        if self.allowance >= 1.0 {
+            self.allowance -= 1.0;
+            if self.dropped > 0 {
+                entries.push(ConsoleLogEntry::Dropped(self.dropped));
+                self.dropped = 0;
+            }
+            let line = String::from_utf8_lossy(&self.line);
+            entries.push(ConsoleLogEntry::Line(line.trim_end_matches('\r').to_owned()));
+        } else {
+            self.dropped += 1;
+        }
+        self.line.clear();
+    }
+
+    /// Write the given entries to the system log.
+    fn log(&self, entries: Vec<ConsoleLogEntry>) {
+        for entry in entries {
+            match entry {
+                ConsoleLogEntry::Line(line) => info!("[{}] {}", self.name, line),
+                ConsoleLogEntry::Dropped(count) => {
+                    warn!("[{}] Dropped {} console lines over rate limit", self.name, count)
+                }
+            }
+        }
+    }
+}
+
+/// Something to be written to the system log by a `ConsoleLogger`.
+#[derive(Debug, Eq, PartialEq)]
+enum ConsoleLogEntry {
+    /// A line of console output, without its line ending.
+    Line(String),
+    /// The number of lines dropped by the rate limit since the last line was logged.
+    Dropped(u64),
+}
+
+/// A source of input for the VM console, which may be replaced at any time.
+#[derive(Debug, Default)]
+struct InputSource {
+    /// The write end of the pipe connected to the stdin of `crosvm`, if it is running.
+    destination: Arc<Mutex<Option<File>>>,
+    /// Incremented every time the source is replaced, so that the thread forwarding from the
+    /// previous source knows to stop.
+    generation: Arc<AtomicUsize>,
+}
+
+impl InputSource {
+    /// Set the pipe to which input is forwarded, replacing any previous one.
+    fn set_destination(&self, destination: File) {
+        *self.destination.lock().unwrap() = Some(destination);
+    }
+
+    /// Replace the file from which input is read, recording any input in `activity`. The previous
+    /// file, if any, is closed by its forwarding thread shortly afterwards. If `file` is `None` then
+    /// no more input is forwarded.
+    fn replace(&self, file: Option<File>, activity: &Arc<Activity>) {
+        let generation = self.generation.fetch_add(1, Ordering::AcqRel) + 1;
+        if let Some(file) = file {
+            let destination = self.destination.clone();
+            let current_generation = self.generation.clone();
+            let activity = activity.clone();
+            thread::spawn(move || {
+                forward_input(file, &destination, &current_generation, generation, &activity);
+            });
+        }
+    }
+
+    /// Stop forwarding input, and close the pipe to `crosvm`. The forwarding thread closes its
+    /// source file shortly afterwards.
+    fn close(&self) {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        *self.destination.lock().unwrap() = None;
+    }
+}
+
+/// Copy from `source` to `destination` until `source` reaches EOF or is replaced by a newer
+/// generation, recording any input in `activity`. Input is discarded while there is no destination.
+fn forward_input(
+    mut source: File,
+    destination: &Mutex<Option<File>>,
+    current_generation: &AtomicUsize,
+    generation: usize,
+    activity: &Activity,
+) {
+    let mut buffer = [0; 1024];
+    while current_generation.load(Ordering::Acquire) == generation {
+        match wait_for(&source, libc::POLLIN, INPUT_POLL_TIMEOUT_MS) {
+            Ok(false) => continue,
+            Ok(true) => {}
+            Err(e) => {
+                error!("Error polling VM console input: {}", e);
+                break;
+            }
+        }
+        let size = match source.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(size) => {
+                activity.record();
+                size
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                error!("Error reading VM console input: {}", e);
+                break;
+            }
+        };
+        let destination = &mut *destination.lock().unwrap();
+        if current_generation.load(Ordering::Acquire) != generation {
+            break;
+        }
+        if let Some(d) = destination {
+            if let Err(e) = d.write_all(&buffer[..size]) {
+                // crosvm has most likely exited; discard input until it is restarted.
+                warn!("Error writing VM console input: {}", e);
+                *destination = None;
+            }
+        }
+    }
+}
+
+/// Write all of `data` to `file`, waiting up to `timeout_ms` milliseconds each time it can't take
+/// more, so that a client which stops reading can't block the caller indefinitely. Writes are no
+/// larger than the forwarding buffer, so they don't block on a pipe which is ready for writing.
+fn write_with_timeout(mut file: &File, mut data: &[u8], timeout_ms: i32) -> io::Result<()> {
+    while !data.is_empty() {
+        if !wait_for(file, libc::POLLOUT, timeout_ms)? {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "Timed out waiting for client"));
+        }
+        match file.write(data) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(size) => data = &data[size..],
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// Wait up to `timeout_ms` milliseconds for any of the given poll events on the given file. Returns
+/// whether one occurred (or the file has hung up or had an error).
+fn wait_for(file: &File, events: libc::c_short, timeout_ms: i32) -> io::Result<bool> {
+    let mut pollfd = libc::pollfd { fd: file.as_raw_fd(), events, revents: 0 };
+    // Safe because we pass a pointer to a single valid pollfd, which the kernel only writes within,
+    // and we check for an error.
+    let ret = unsafe { libc::poll(&mut pollfd, 1, timeout_ms) };
+    match ret {
+        r if r < 0 => {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(e)
+            }
+        }
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+/// Create a new pipe, returning its read and write ends.
+fn pipe() -> io::Result<(File, File)> {
+    let mut fds = [0; 2];
+    // Safe because we pass a pointer to an array of two fds, which is what pipe2 expects, and we
+    // check for an error.
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safe because pipe2 has just given us these two file descriptors, so we own them, and
+    // `from_raw_fd` takes ownership of them.
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(line: &str) -> ConsoleLogEntry {
+        ConsoleLogEntry::Line(line.to_owned())
+    }
+
+    #[test]
+    fn test_logger_splits_lines() {
+        let mut logger = ConsoleLogger::new("vm:10".to_owned());
+        let now = Instant::now();
+        assert_eq!(logger.write(b"first\r\nsec", now), vec![line("first")]);
+        assert_eq!(logger.write(b"ond\n\nthi", now), vec![line("second")]);
+        assert_eq!(logger.flush(now), vec![line("thi")]);
+        assert_eq!(logger.flush(now), vec![]);
+    }
+
+    #[test]
+    fn test_logger_splits_long_lines() {
+        let mut logger = ConsoleLogger::new("vm:10".to_owned());
+        let now = Instant::now();
+        let data = vec![b'a'; CONSOLE_LOG_MAX_LINE_LENGTH + 10];
+        let entries = logger.write(&data, now);
+        assert_eq!(entries, vec![line(&"a".repeat(CONSOLE_LOG_MAX_LINE_LENGTH))]);
+        assert_eq!(logger.flush(now), vec![line(&"a".repeat(10))]);
+    }
+
+    #[test]
+    fn test_logger_rate_limit() {
+        let mut logger = ConsoleLogger::new("vm:10".to_owned());
+        let start = Instant::now();
+        let burst = CONSOLE_LOG_BURST_LINES as usize;
+
+        // A full burst is logged at once, and anything more is dropped.
+        let entries = logger.write("x\n".repeat(burst + 5).as_bytes(), start);
+        assert_eq!(entries.len(), burst);
+        assert!(entries.iter().all(|entry| *entry == line("x")));
+
+        // The allowance is replenished over time, and the first line logged afterwards is preceded
+        // by the number dropped.
+        let later = start + Duration::from_secs(1);
+        let entries = logger.write("y\n".repeat(100).as_bytes(), later);
+        let replenished = CONSOLE_LOG_LINES_PER_SEC as usize;
+        assert_eq!(entries.len(), replenished + 1);
+        assert_eq!(entries[0], ConsoleLogEntry::Dropped(5));
+        assert!(entries[1..].iter().all(|entry| *entry == line("y")));
+    }
+
+    #[test]
+    fn test_drop_stops_input_forwarding() {
+        let (console, mut stdio) = Console::new(None, "vm:10".to_owned()).unwrap();
+        let (client_in, mut client) = pipe().unwrap();
+        console.set_fds(None, Some(client_in), None);
+        client.write_all(b"x").unwrap();
+        let mut buffer = [0; 1];
+        stdio.stdin.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"x");
+
+        // The forwarding thread closes the client's file, and the pipe to crosvm is closed.
+        drop(console);
+        let start = Instant::now();
+        while client.write(b"x").is_ok() {
+            assert!(start.elapsed() < Duration::from_secs(5), "Input forwarding didn't stop");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(stdio.stdin.read(&mut buffer).unwrap(), 0);
+    }
+}
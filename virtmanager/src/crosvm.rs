@@ -15,24 +15,93 @@
 //! Functions for running instances of `crosvm`.
 
 use crate::aidl::VirtualMachineCallbacks;
-use crate::config::VmConfig;
+use crate::cgroup::{CpuSet, ResourceUsage, VmCgroup};
+use crate::config::{VmConfig, VmPriority};
+use crate::console::{ChildStdio, Console, ConsolePort};
+use crate::net::TapDevice;
+use crate::sched::set_vcpu_priority;
+use crate::snapshot::Snapshot;
 use crate::Cid;
-use anyhow::Error;
-use log::{error, info};
+use android_system_virtmanager::aidl::android::system::virtmanager::DeathReason::DeathReason;
+use anyhow::{bail, Context, Error};
+use log::{error, info, warn};
+use serde::Deserialize;
 use shared_child::SharedChild;
-use std::fs::File;
-use std::process::Command;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
 
 const CROSVM_PATH: &str = "/apex/com.android.virt/bin/crosvm";
 
+/// The directory in which the control sockets of crosvm instances are created. This is created by
+/// the platform init.rc, as only init can create directories in /data/misc.
+const CROSVM_CONTROL_SOCKET_DIR: &str = "/data/misc/virtmanager";
+
+/// The directory in which the vmm-swap directories of crosvm instances are created.
+const CROSVM_SWAP_DIR: &str = "/data/misc/virtmanager/swap";
+
+/// The exit status which crosvm returns when the VM requests a reboot.
+const CROSVM_REBOOT_STATUS: i32 = 32;
+/// The exit status which crosvm returns when it crashes.
+const CROSVM_CRASH_STATUS: i32 = 33;
+/// The exit status which crosvm returns when the guest kernel panics, as reported by the pvpanic
+/// device.
+const CROSVM_GUEST_PANIC_STATUS: i32 = 34;
+
+/// The number of lines of console output to include in a crash report.
+const CRASH_REPORT_CONSOLE_LINES: usize = 100;
+
+/// How long to wait for a crosvm control command before killing it.
+const CONTROL_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for crosvm to take a snapshot, which involves writing all guest memory.
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(300);
+/// How often to check whether a crosvm control command has finished.
+const CONTROL_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The number of bytes in a MiB.
+const MIB: u64 = 1 << 20;
+
+/// How often to check whether a VM with an idle suspend timeout should be suspended or resumed.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often to check the guest's page fault counts reported by the memory balloon of a VM with an
+/// idle suspend timeout, other than just before suspending it. Each check runs a crosvm process.
+const IDLE_BALLOON_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The CPU time in microseconds which crosvm may use in one `IDLE_CHECK_INTERVAL` without this
+/// counting as activity, to allow for guest timer ticks and housekeeping.
+const IDLE_MAX_CPU_TIME_US: u64 = 20_000;
+
+/// The OOM killer score adjustment for crosvm processes of background VMs. This is PREVIOUS_APP_ADJ,
+/// so they are killed after cached apps but before services and the home app.
+const BACKGROUND_OOM_SCORE_ADJ: i32 = 700;
+/// The OOM killer score adjustment for crosvm processes of idle VMs. This is CACHED_APP_MIN_ADJ, so
+/// they are killed after most cached apps, which have higher scores.
+const IDLE_OOM_SCORE_ADJ: i32 = 900;
+
+/// The magic number at the start of qcow2 disk images.
+const QCOW2_MAGIC: [u8; 4] = *b"QFI\xfb";
+
+/// The prefix of the console line which the Linux kernel prints when it panics.
+const KERNEL_PANIC_PREFIX: &str = "Kernel panic - not syncing: ";
+
+/// The sysfs file showing the mode in which transparent hugepages are enabled on the host.
+const TRANSPARENT_HUGEPAGE_ENABLED_PATH: &str = "/sys/kernel/mm/transparent_hugepage/enabled";
+
 /// Information about a particular instance of a VM which is running.
 #[derive(Debug)]
 pub struct VmInstance {
-    /// The crosvm child process.
-    child: SharedChild,
+    /// The crosvm child process. This is replaced if the VM is restarted after a guest reboot.
+    child: Mutex<Arc<SharedChild>>,
     /// The CID assigned to the VM for vsock communication.
     pub cid: Cid,
     /// The UID of the process which requested the VM.
@@ -42,68 +111,319 @@ pub struct VmInstance {
     /// The PID of the process which requested the VM. Note that this process may no longer exist
     /// and the PID may have been reused for a different process, so this should not be trusted.
     pub requester_debug_pid: i32,
+    /// The labels given to the VM in its config.
+    pub labels: BTreeMap<String, String>,
     /// Whether the VM is still running.
     running: AtomicBool,
+    /// Whether Virt Manager has killed the VM.
+    killed: AtomicBool,
+    /// Whether Virt Manager has suspended the VM because it was idle.
+    idle_suspended: Mutex<bool>,
     /// Callbacks to clients of the VM.
     pub callbacks: VirtualMachineCallbacks,
+    /// The console and log streams of the VM.
+    console: Console,
+    /// Resources set up by Virt Manager for the VM.
+    resources: VmResources,
+    /// Host USB devices attached to the VM, by the port to which they are attached.
+    usb_devices: Mutex<BTreeMap<u8, File>>,
+    /// The config with which the VM was started.
+    config: VmConfig,
 }
 
-impl VmInstance {
-    /// Create a new `VmInstance` for the given process.
-    fn new(
-        child: SharedChild,
-        cid: Cid,
-        requester_uid: u32,
-        requester_sid: String,
-        requester_debug_pid: i32,
-    ) -> VmInstance {
-        VmInstance {
-            child,
-            cid,
-            requester_uid,
-            requester_sid,
-            requester_debug_pid,
-            running: AtomicBool::new(true),
-            callbacks: Default::default(),
+/// Resources which Virt Manager sets up for a VM, and passes to each crosvm process run for it.
+#[derive(Debug)]
+struct VmResources {
+    /// Whether the guest memory of the VM is backed by transparent hugepages.
+    hugepages: bool,
+    /// The path of the socket on which crosvm listens for control commands.
+    control_socket: PathBuf,
+    /// The TAP device providing the VM's network, if it has one.
+    tap: Option<TapDevice>,
+    /// The extra virtio-console ports of the VM.
+    console_ports: Vec<ConsolePort>,
+    /// The cgroup accounting for the resources used by crosvm, if it could be created.
+    cgroup: Option<VmCgroup>,
+    /// The cpuset restricting crosvm to the host CPUs in the VM's CPU affinity, if it has one and
+    /// the cpuset could be created.
+    cpuset: Option<CpuSet>,
+    /// The directory in which crosvm creates its vmm-swap file, if vmm-swap is enabled.
+    swap_dir: Option<PathBuf>,
+}
+
+impl Drop for VmResources {
+    fn drop(&mut self) {
+        // crosvm doesn't remove its control socket when it exits.
+        if let Err(e) = fs::remove_file(&self.control_socket) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Failed to remove control socket {:?}: {}", self.control_socket, e);
+            }
+        }
+        if let Some(swap_dir) = &self.swap_dir {
+            if let Err(e) = fs::remove_dir_all(swap_dir) {
+                warn!("Failed to remove swap directory {:?}: {}", swap_dir, e);
+            }
         }
     }
+}
+
+/// Diagnostics collected when crosvm exits abnormally.
+#[derive(Clone, Debug)]
+pub struct CrashReport {
+    /// The exit status of crosvm, if it exited rather than being killed by a signal.
+    pub exit_status: Option<i32>,
+    /// The signal which killed crosvm, if any.
+    pub signal: Option<i32>,
+    /// The most recent output of crosvm to its standard error.
+    pub crosvm_stderr: String,
+    /// The last lines of output from the VM's console.
+    pub console_tail: String,
+}
 
-    /// Start an instance of `crosvm` to manage a new VM. The `crosvm` instance will be killed when
-    /// the `VmInstance` is dropped.
+/// Statistics reported by the memory balloon of a VM, in the form output by `crosvm balloon_stats`.
+/// Each statistic may be missing if the guest did not report it.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct BalloonStats {
+    pub swap_in: Option<u64>,
+    pub swap_out: Option<u64>,
+    pub major_faults: Option<u64>,
+    pub minor_faults: Option<u64>,
+    pub free_memory: Option<u64>,
+    pub total_memory: Option<u64>,
+    pub available_memory: Option<u64>,
+    pub disk_caches: Option<u64>,
+    /// The current size of the balloon, in bytes.
+    #[serde(skip)]
+    pub balloon_actual: u64,
+}
+
+impl VmInstance {
+    /// Start an instance of `crosvm` to manage a new VM, optionally restoring its state from a
+    /// snapshot. The `crosvm` instance will be killed when the `VmInstance` is dropped.
     pub fn start(
         config: &VmConfig,
         cid: Cid,
-        log_fd: Option<File>,
+        console_out_fd: Option<File>,
         requester_uid: u32,
         requester_sid: String,
         requester_debug_pid: i32,
+        restore_from: Option<&Snapshot>,
     ) -> Result<Arc<VmInstance>, Error> {
-        let child = run_vm(config, cid, log_fd)?;
-        let instance = Arc::new(VmInstance::new(
-            child,
+        let log_name = format!("{}:{}", config.name.as_deref().unwrap_or("vm"), cid);
+        let (console, child_stdio) = Console::new(console_out_fd, log_name)?;
+        let hugepages = config.use_hugepages && transparent_hugepages_available();
+        if config.use_hugepages && !hugepages {
+            warn!("Transparent hugepages are not available, falling back to normal pages.");
+        }
+        let tap = if config.network_supported { Some(TapDevice::create(cid)?) } else { None };
+        let console_ports = config
+            .console_ports
+            .iter()
+            .map(|name| ConsolePort::new(name.clone()))
+            .collect::<io::Result<_>>()
+            .context("Failed to create console ports")?;
+        // Resource accounting is best effort, so the VM can still run without a cgroup.
+        let cgroup = VmCgroup::create(cid, vm_priority(config))
+            .map_err(|e| warn!("Failed to create cgroup for VM {}: {:?}", cid, e))
+            .ok();
+        // crosvm pins the vCPU threads itself, so the cpuset only restricts its other threads.
+        let cpuset = if config.cpu_affinity.is_empty() {
+            None
+        } else {
+            CpuSet::create(cid, &config.cpu_affinity)
+                .map_err(|e| warn!("Failed to create cpuset for VM {}: {:?}", cid, e))
+                .ok()
+        };
+        let swap_dir = if config.vmm_swap { Some(create_swap_dir(cid)?) } else { None };
+        let resources = VmResources {
+            hugepages,
+            control_socket: control_socket_path(cid),
+            tap,
+            console_ports,
+            cgroup,
+            cpuset,
+            swap_dir,
+        };
+        let restore_from = restore_from.map(Snapshot::state_path);
+        let child = run_vm(config, cid, child_stdio, &resources, restore_from.as_deref())?;
+        let instance = Arc::new(VmInstance {
+            child: Mutex::new(Arc::new(child)),
             cid,
             requester_uid,
             requester_sid,
             requester_debug_pid,
-        ));
+            labels: config.labels.clone(),
+            running: AtomicBool::new(true),
+            killed: AtomicBool::new(false),
+            idle_suspended: Mutex::new(false),
+            callbacks: Default::default(),
+            console,
+            resources,
+            usb_devices: Default::default(),
+            config: config.clone(),
+        });
 
         let instance_clone = instance.clone();
         thread::spawn(move || {
             instance_clone.monitor();
         });
+        if let Some(timeout_secs) = config.idle_suspend_timeout_secs {
+            let instance_clone = instance.clone();
+            thread::spawn(move || {
+                instance_clone.monitor_idle(Duration::from_secs(timeout_secs));
+            });
+        }
 
         Ok(instance)
     }
 
     /// Wait for the crosvm child process to finish, then mark the VM as no longer running and call
-    /// any callbacks.
+    /// any callbacks. If the guest rebooted and the VM is configured for warm reboot, crosvm is
+    /// restarted instead.
     fn monitor(&self) {
-        match self.child.wait() {
-            Err(e) => error!("Error waiting for crosvm instance to die: {}", e),
-            Ok(status) => info!("crosvm exited with status {}", status),
-        }
+        let (death_reason, result) = loop {
+            let child = self.child.lock().unwrap().clone();
+            let result = child.wait();
+            match &result {
+                Err(e) => error!("Error waiting for crosvm instance to die: {}", e),
+                Ok(status) => info!("crosvm exited with status {}", status),
+            }
+            // Make sure the console output is complete before looking at it or reconnecting it.
+            self.console.wait_for_eof();
+
+            let death_reason = death_reason(&result, self.killed.load(Ordering::Acquire));
+            if death_reason != DeathReason::REBOOT || !self.config.warm_reboot {
+                break (death_reason, result);
+            }
+            match self.restart() {
+                Ok(()) => info!("Restarted VM {} after guest reboot", self.cid),
+                // The VM may have been killed since `death_reason` was worked out.
+                Err(_) if self.killed.load(Ordering::Acquire) => {
+                    break (DeathReason::KILLED, result);
+                }
+                Err(e) => {
+                    error!("Failed to restart VM {} after guest reboot: {:?}", self.cid, e);
+                    break (death_reason, result);
+                }
+            }
+        };
         self.running.store(false, Ordering::Release);
-        self.callbacks.callback_on_died(self.cid);
+        self.release_usb_devices();
+
+        if death_reason == DeathReason::GUEST_PANIC {
+            let message = find_panic_message(&self.console.console_tail());
+            self.callbacks.callback_on_guest_panic(self.cid, message);
+        }
+        if death_reason == DeathReason::CRASH || death_reason == DeathReason::UNKNOWN {
+            if let Ok(status) = &result {
+                self.callbacks.callback_on_crash(self.cid, &self.crash_report(status));
+            }
+        }
+        let usage = self
+            .resource_usage()
+            .map_err(|e| error!("Failed to get resource usage of VM {}: {:?}", self.cid, e))
+            .ok();
+        self.callbacks.callback_on_died(self.cid, death_reason, usage.as_ref());
+    }
+
+    /// Collect diagnostics about crosvm having exited abnormally with the given status.
+    fn crash_report(&self, status: &ExitStatus) -> CrashReport {
+        let console_tail = self.console.console_tail();
+        let lines = console_tail.lines().collect::<Vec<_>>();
+        let first_line = lines.len().saturating_sub(CRASH_REPORT_CONSOLE_LINES);
+        CrashReport {
+            exit_status: status.code(),
+            signal: status.signal(),
+            crosvm_stderr: self.console.log_tail(),
+            console_tail: lines[first_line..].join("\n"),
+        }
+    }
+
+    /// Suspend the VM whenever it has been idle for the given timeout, and resume it when there is
+    /// activity again, until it stops running. Use of more than a little CPU time by crosvm and
+    /// changes in the guest's page fault counts reported by the memory balloon, if any, count as
+    /// activity.
+    fn monitor_idle(&self, timeout: Duration) {
+        let mut last_faults = None;
+        let mut last_balloon_check = Instant::now();
+        let mut last_cpu_time_us = None;
+        while self.running() {
+            thread::sleep(IDLE_CHECK_INTERVAL);
+            // Only hold `idle_suspended` to suspend or resume the VM, so that a slow check doesn't
+            // hold up clients calling `wake`.
+            if *self.idle_suspended.lock().unwrap() {
+                last_cpu_time_us = None;
+            } else {
+                let cpu_time_us = self.cpu_time_us().ok();
+                if let (Some(last), Some(now)) = (last_cpu_time_us, cpu_time_us) {
+                    if now.saturating_sub(last) > IDLE_MAX_CPU_TIME_US {
+                        self.console.record_activity();
+                    }
+                }
+                last_cpu_time_us = cpu_time_us;
+                // Fetching balloon stats runs crosvm, so do it rarely unless the VM is about to be
+                // suspended.
+                if self.config.balloon
+                    && (last_balloon_check.elapsed() >= IDLE_BALLOON_CHECK_INTERVAL
+                        || self.console.idle_duration() >= timeout)
+                {
+                    last_balloon_check = Instant::now();
+                    if let Ok(stats) = self.fetch_balloon_stats() {
+                        let faults = stats.major_faults.zip(stats.minor_faults);
+                        if last_faults.is_some() && faults != last_faults {
+                            self.console.record_activity();
+                        }
+                        last_faults = faults;
+                    }
+                }
+            }
+            let suspended = &mut *self.idle_suspended.lock().unwrap();
+            // Check this with the lock held, as a client may have woken the VM meanwhile.
+            let idle = self.console.idle_duration() >= timeout;
+            if idle == *suspended {
+                continue;
+            }
+            let command = if idle { "suspend" } else { "resume" };
+            match self.control(&[command]) {
+                Ok(_) => {
+                    info!("Ran {} on VM {} after {:?} idle", command, self.cid, timeout);
+                    *suspended = idle;
+                }
+                Err(e) => error!("Failed to {} idle VM {}: {:?}", command, self.cid, e),
+            }
+        }
+    }
+
+    /// Record activity from a client of the VM, resuming it first if it has been suspended for being
+    /// idle.
+    pub fn wake(&self) -> Result<(), Error> {
+        let suspended = &mut *self.idle_suspended.lock().unwrap();
+        self.console.record_activity();
+        if *suspended {
+            self.control(&["resume"])?;
+            *suspended = false;
+            info!("Resumed idle VM {}", self.cid);
+        }
+        Ok(())
+    }
+
+    /// Run a new crosvm process for the VM after the previous one has exited, keeping the same
+    /// config, CID, console and other resources.
+    fn restart(&self) -> Result<(), Error> {
+        let child = &mut *self.child.lock().unwrap();
+        // Check this while holding the lock, so that we can't race with `kill`.
+        if self.killed.load(Ordering::Acquire) {
+            bail!("VM has been killed");
+        }
+        // The new crosvm process starts running, whether or not the old one was suspended.
+        *self.idle_suspended.lock().unwrap() = false;
+        self.console.record_activity();
+        // USB devices attached to the old crosvm process were detached when it exited.
+        self.release_usb_devices();
+        let child_stdio = self.console.connect()?;
+        let new_child = run_vm(&self.config, self.cid, child_stdio, &self.resources, None)?;
+        *child = Arc::new(new_child);
+        Ok(())
     }
 
     /// Return whether `crosvm` is still running the VM.
@@ -111,27 +431,352 @@ impl VmInstance {
         self.running.load(Ordering::Acquire)
     }
 
+    /// Return the resources used by the VM so far.
+    pub fn resource_usage(&self) -> Result<ResourceUsage, Error> {
+        let cgroup = self.resources.cgroup.as_ref().context("VM has no cgroup")?;
+        let mut usage = cgroup.resource_usage()?;
+        if usage.peak_memory_bytes.is_none() && self.running() {
+            // Fall back to the peak RSS of the current crosvm process, which doesn't include any
+            // previous ones or their children.
+            usage.peak_memory_bytes = peak_rss_bytes(self.child.lock().unwrap().id());
+        }
+        Ok(usage)
+    }
+
+    /// Return the CPU time used by the VM so far in microseconds, from its cgroup if it has one or
+    /// otherwise from the current crosvm process.
+    fn cpu_time_us(&self) -> Result<u64, Error> {
+        match &self.resources.cgroup {
+            Some(cgroup) => cgroup.cpu_time_us(),
+            None => process_cpu_time_us(self.child.lock().unwrap().id()),
+        }
+    }
+
+    /// Return whether the guest memory of the VM is backed by transparent hugepages.
+    pub fn hugepages(&self) -> bool {
+        self.resources.hugepages
+    }
+
+    /// Return the name of the host side interface of the VM's TAP device, if it has one. The
+    /// interface is in a network namespace of its own.
+    pub fn tap_interface_name(&self) -> Option<&str> {
+        self.resources.tap.as_ref().map(|tap| tap.name.as_str())
+    }
+
+    /// Return the network namespace containing the VM's TAP device, if it has one.
+    pub fn network_namespace(&self) -> Option<&File> {
+        self.resources.tap.as_ref().map(|tap| &tap.netns)
+    }
+
+    /// Return the extra virtio-console ports of the VM.
+    pub fn console_ports(&self) -> &[ConsolePort] {
+        &self.resources.console_ports
+    }
+
+    /// Attach or replace the files used for the VM's console and crosvm's log. `None` detaches the
+    /// corresponding stream.
+    pub fn set_console_fds(
+        &self,
+        console_out_fd: Option<File>,
+        console_in_fd: Option<File>,
+        log_fd: Option<File>,
+    ) -> Result<(), Error> {
+        self.console.set_fds(console_out_fd, console_in_fd, log_fd);
+        if self.running() {
+            self.wake()?;
+        }
+        Ok(())
+    }
+
+    /// Set the target size of the VM's memory balloon, in bytes.
+    pub fn set_memory_balloon(&self, num_bytes: u64) -> Result<(), Error> {
+        self.wake()?;
+        self.control(&["balloon", &num_bytes.to_string()])?;
+        Ok(())
+    }
+
+    /// Change the amount of memory available to the guest to the given size in MiB, up to the
+    /// maximum in the VM's config, by resizing the memory balloon to hold the rest.
+    pub fn set_memory_mib(&self, memory_mib: u32) -> Result<(), Error> {
+        let max_memory_mib = match self.config.max_memory_mib {
+            Some(max_memory_mib) => max_memory_mib,
+            None => bail!("VM was not configured with a maximum memory size"),
+        };
+        if memory_mib == 0 || memory_mib > max_memory_mib {
+            bail!("Memory size {} MiB is not in range 1..={} MiB", memory_mib, max_memory_mib);
+        }
+        self.set_memory_balloon(u64::from(max_memory_mib - memory_mib) * MIB)
+    }
+
+    /// Get the current statistics of the VM's memory balloon.
+    pub fn memory_balloon_stats(&self) -> Result<BalloonStats, Error> {
+        self.wake()?;
+        self.fetch_balloon_stats()
+    }
+
+    /// Get the current statistics of the VM's memory balloon, without counting this as activity.
+    fn fetch_balloon_stats(&self) -> Result<BalloonStats, Error> {
+        #[derive(Deserialize)]
+        struct BalloonStatsResponse {
+            stats: BalloonStats,
+            balloon_actual: u64,
+        }
+        #[derive(Deserialize)]
+        enum Response {
+            BalloonStats(BalloonStatsResponse),
+        }
+
+        let output = self.control(&["balloon_stats"])?;
+        let Response::BalloonStats(response) = serde_json::from_str(&output)
+            .with_context(|| format!("Failed to parse balloon stats {:?}", output))?;
+        Ok(BalloonStats { balloon_actual: response.balloon_actual, ..response.stats })
+    }
+
+    /// Save a snapshot of the VM, from which it can later be restored. The VM is suspended while the
+    /// snapshot is taken.
+    pub fn snapshot(&self, snapshot: &Snapshot) -> Result<(), Error> {
+        // The images would carry on changing after the snapshot, so it couldn't be restored.
+        let mut disks = self.config.disks.iter().chain(&self.config.pmem_disks);
+        if let Some(disk) = disks.find(|disk| disk.writable) {
+            bail!("Can't snapshot VM with writable disk image {:?}", disk.image);
+        }
+        // Hold this throughout so that the idle monitor can't suspend or resume the VM meanwhile.
+        let suspended = &mut *self.idle_suspended.lock().unwrap();
+        self.console.record_activity();
+        snapshot.create(&self.config, self.cid)?;
+        let state_path = snapshot.state_path();
+        let state_path = state_path.to_str().context("Snapshot path is not valid UTF-8")?;
+        if !*suspended {
+            self.control(&["suspend"])?;
+        }
+        let result = self.run_control(&["snapshot", "take", state_path], &[], SNAPSHOT_TIMEOUT);
+        // Resume the VM even if taking the snapshot failed, but report that failure first.
+        let resumed = self.control(&["resume"]);
+        *suspended = resumed.is_err();
+        result?;
+        resumed?;
+        Ok(())
+    }
+
+    /// Grow the disk with the given index in the VM's config to the given size in bytes. crosvm
+    /// resizes the image according to its format, so the image file is only read here, to check
+    /// that the disk isn't being shrunk.
+    pub fn resize_disk(&self, index: usize, new_size: u64) -> Result<(), Error> {
+        let disk = self.config.disks.get(index).with_context(|| format!("No disk {}", index))?;
+        if !disk.writable {
+            bail!("Disk {} is not writable", index);
+        }
+        let size = disk_size(Path::new(&disk.image))?;
+        if new_size < size {
+            bail!("Can't shrink disk {} from {} to {} bytes", index, size, new_size);
+        }
+        self.wake()?;
+        self.control(&["disk", "resize", &index.to_string(), &new_size.to_string()])?;
+        Ok(())
+    }
+
+    /// Enable or disable vmm-swap for the VM. While it is enabled, crosvm moves guest memory which is
+    /// not being accessed out of the VM, so that it can be written to disk by `trim_swap`.
+    pub fn set_swap_enabled(&self, enabled: bool) -> Result<(), Error> {
+        if self.resources.swap_dir.is_none() {
+            bail!("VM was not configured with vmm-swap");
+        }
+        self.control(&["swap", if enabled { "enable" } else { "disable" }])?;
+        Ok(())
+    }
+
+    /// Drop guest memory which vmm-swap can restore without the swap file, then write the rest of
+    /// the memory staged by vmm-swap to disk.
+    pub fn trim_swap(&self) -> Result<(), Error> {
+        if self.resources.swap_dir.is_none() {
+            bail!("VM was not configured with vmm-swap");
+        }
+        self.control(&["swap", "trim"])?;
+        self.control(&["swap", "out"])?;
+        Ok(())
+    }
+
+    /// Attach the host USB device with the given usbfs file to the VM, returning the port to which it
+    /// was attached.
+    pub fn attach_usb_device(&self, device: File) -> Result<u8, Error> {
+        self.wake()?;
+        let device_id = usb_device_id(&device)?;
+        let device_path = format!("/proc/self/fd/{}", device.as_raw_fd());
+        // Hold the lock while attaching, so that the device can't be leaked if the VM dies
+        // concurrently.
+        let mut usb_devices = self.usb_devices.lock().unwrap();
+        let output = self.run_control(
+            &["usb", "attach", &device_id, &device_path],
+            &[&device],
+            CONTROL_TIMEOUT,
+        )?;
+        let port = output
+            .trim()
+            .strip_prefix("ok ")
+            .and_then(|port| port.parse().ok())
+            .with_context(|| format!("Failed to attach USB device: {}", output.trim()))?;
+        usb_devices.insert(port, device);
+        Ok(port)
+    }
+
+    /// Detach the host USB device attached to the given port of the VM.
+    pub fn detach_usb_device(&self, port: u8) -> Result<(), Error> {
+        self.wake()?;
+        let mut usb_devices = self.usb_devices.lock().unwrap();
+        if !usb_devices.contains_key(&port) {
+            bail!("No USB device attached to port {}", port);
+        }
+        let output = self.control(&["usb", "detach", &port.to_string()])?;
+        if !output.starts_with("ok") {
+            bail!("Failed to detach USB device: {}", output.trim());
+        }
+        usb_devices.remove(&port);
+        Ok(())
+    }
+
+    /// Close the files of all USB devices attached to the VM, once crosvm has exited.
+    fn release_usb_devices(&self) {
+        let mut usb_devices = self.usb_devices.lock().unwrap();
+        for port in usb_devices.keys() {
+            info!("Detached USB device on port {} of VM {}", port, self.cid);
+        }
+        usb_devices.clear();
+    }
+
+    /// Run the given crosvm control command against this VM, returning its standard output.
+    fn control(&self, args: &[&str]) -> Result<String, Error> {
+        self.run_control(args, &[], CONTROL_TIMEOUT)
+    }
+
+    /// Run the given crosvm control command against this VM, letting it inherit the given files,
+    /// and returning its standard output. The command is killed if it takes longer than the given
+    /// timeout, so that a wedged crosvm can't block its callers forever.
+    fn run_control(
+        &self,
+        args: &[&str],
+        files: &[&File],
+        timeout: Duration,
+    ) -> Result<String, Error> {
+        if !self.running() {
+            bail!("VM is not running");
+        }
+        let mut command = crosvm_command();
+        for file in files {
+            preserve_fd(&mut command, file);
+        }
+        let mut child = command
+            .args(args)
+            .arg(&self.resources.control_socket)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run crosvm {:?}", args))?;
+        // Control commands only write a line or so, which fits in the pipes until they exit.
+        wait_with_timeout(&mut child, timeout)
+            .with_context(|| format!("Failed to wait for crosvm {:?}", args))?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            bail!(
+                "crosvm {:?} failed with {}: {}",
+                args,
+                output.status,
+                String::from_utf8_lossy(&output.stderr)
+            );
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
     /// Kill the crosvm instance.
     pub fn kill(&self) {
         // TODO: Talk to crosvm to shutdown cleanly.
-        if let Err(e) = self.child.kill() {
+        let child = self.child.lock().unwrap();
+        self.killed.store(true, Ordering::Release);
+        if let Err(e) = child.kill() {
             error!("Error killing crosvm instance: {}", e);
         }
     }
 }
 
 /// Start an instance of `crosvm` to manage a new VM.
-fn run_vm(config: &VmConfig, cid: Cid, log_fd: Option<File>) -> Result<SharedChild, Error> {
+fn run_vm(
+    config: &VmConfig,
+    cid: Cid,
+    stdio: ChildStdio,
+    resources: &VmResources,
+    restore_from: Option<&Path>,
+) -> Result<SharedChild, Error> {
     config.validate()?;
 
-    let mut command = Command::new(CROSVM_PATH);
+    let control_socket = &resources.control_socket;
+    // Remove any stale socket left behind by a previous crosvm instance with the same CID.
+    if control_socket.exists() {
+        fs::remove_file(control_socket)
+            .with_context(|| format!("Failed to remove stale socket {:?}", control_socket))?;
+    }
+
+    let mut command = crosvm_command();
     // TODO(qwandor): Remove --disable-sandbox.
     command.arg("run").arg("--disable-sandbox").arg("--cid").arg(cid.to_string());
-    if let Some(log_fd) = log_fd {
-        command.stdout(log_fd);
-    } else {
-        // Ignore console output.
-        command.arg("--serial=type=sink");
+    command.arg("--socket").arg(control_socket);
+    // The console and log are forwarded by Virt Manager, so that clients can attach them later.
+    command.stdin(stdio.stdin).stdout(stdio.stdout).stderr(stdio.stderr);
+    // Let crosvm report guest kernel panics through its exit status.
+    command.arg("--pvpanic");
+    if resources.hugepages {
+        command.arg("--hugepages");
+    }
+    match (config.memory_mib, config.max_memory_mib) {
+        // Memory beyond the initial size starts out in the balloon.
+        (Some(memory_mib), Some(max_memory_mib)) => {
+            command.arg("--mem").arg(max_memory_mib.to_string());
+            command.arg("--init-mem").arg(memory_mib.to_string());
+        }
+        (Some(memory_mib), None) => {
+            command.arg("--mem").arg(memory_mib.to_string());
+        }
+        (None, _) => {}
+    }
+    if !config.balloon {
+        command.arg("--no-balloon");
+    }
+    if let Some(gdb_port) = config.gdb_port {
+        command.arg("--gdb").arg(gdb_port.to_string());
+    }
+    if let Some(audio_config) = &config.audio_config {
+        // The AAudio backend routes audio through the audio HAL like any other Android app.
+        command.arg("--virtio-snd").arg(format!(
+            "backend=aaudio,capture={},num_output_devices={},num_input_devices={}",
+            audio_config.use_microphone,
+            audio_config.use_speaker as u32,
+            audio_config.use_microphone as u32
+        ));
+    }
+    // The main console is the serial device on crosvm's standard streams, so virtio-console ports
+    // are numbered from 1.
+    for (i, port) in resources.console_ports.iter().enumerate() {
+        let guest_out = preserve_fd(&mut command, &port.guest_out);
+        let guest_in = preserve_fd(&mut command, &port.guest_in);
+        command.arg("--serial").arg(format!(
+            "type=file,hardware=virtio-console,num={},path=/proc/self/fd/{},input=/proc/self/fd/{},name={}",
+            i + 1,
+            guest_out,
+            guest_in,
+            port.name
+        ));
+    }
+    if !config.cpu_affinity.is_empty() {
+        let cpus = config.cpu_affinity.iter().map(u32::to_string).collect::<Vec<_>>().join(",");
+        command.arg("--cpu-affinity").arg(cpus);
+    }
+    if let Some(swap_dir) = &resources.swap_dir {
+        command.arg("--swap").arg(swap_dir);
+    }
+    if let Some(tap) = &resources.tap {
+        let tap_fd = preserve_fd(&mut command, &tap.file);
+        command.arg("--tap-fd").arg(tap_fd.to_string());
+    }
+    if let Some(restore_from) = restore_from {
+        command.arg("--restore").arg(restore_from);
     }
     if let Some(bootloader) = &config.bootloader {
         command.arg("--bios").arg(bootloader);
@@ -145,9 +790,331 @@ fn run_vm(config: &VmConfig, cid: Cid, log_fd: Option<File>) -> Result<SharedChi
     for disk in &config.disks {
         command.arg(if disk.writable { "--rwdisk" } else { "--disk" }).arg(&disk.image);
     }
+    for pmem_disk in &config.pmem_disks {
+        command
+            .arg(if pmem_disk.writable { "--rw-pmem-device" } else { "--pmem-device" })
+            .arg(&pmem_disk.image);
+    }
     if let Some(kernel) = &config.kernel {
         command.arg(kernel);
     }
+    if let Some(cgroup) = &resources.cgroup {
+        match cgroup.open_procs() {
+            Ok(procs) => join_cgroup_on_exec(&mut command, procs),
+            Err(e) => warn!("Failed to open cgroup.procs: {:?}", e),
+        }
+    }
     info!("Running {:?}", command);
-    Ok(SharedChild::spawn(&mut command)?)
+    let child = SharedChild::spawn(&mut command)?;
+    // This does nothing if crosvm has joined the cgroup already, but warns if it couldn't.
+    if let Some(cgroup) = &resources.cgroup {
+        if let Err(e) = cgroup.add_process(child.id()) {
+            warn!("Failed to move crosvm into cgroup, so its resources won't be counted: {:?}", e);
+        }
+    }
+    if let Some(cpuset) = &resources.cpuset {
+        if let Err(e) = cpuset.add_process(child.id()) {
+            warn!("Failed to move crosvm into cpuset, relying on --cpu-affinity alone: {:?}", e);
+        }
+    }
+    if let Err(e) = set_oom_score_adj(child.id(), vm_priority(config)) {
+        // Don't leave the VM running with the wrong OOM priority.
+        if let Err(e) = child.kill().and_then(|()| child.wait()) {
+            error!("Error killing crosvm instance: {}", e);
+        }
+        return Err(e);
+    }
+    if let Some(priority) = config.scheduler_priority {
+        set_vcpu_priority(child.id(), priority);
+    }
+    Ok(child)
+}
+
+/// Return the priority class of the VM with the given config.
+fn vm_priority(config: &VmConfig) -> VmPriority {
+    config.vm_priority.unwrap_or(VmPriority::Foreground)
+}
+
+/// Set the OOM killer score adjustment of the crosvm process with the given PID according to the
+/// priority class of its VM. Virt Manager can only raise this, not lower it below its own.
+fn set_oom_score_adj(pid: u32, priority: VmPriority) -> Result<(), Error> {
+    let oom_score_adj = match priority {
+        VmPriority::Foreground => return Ok(()),
+        VmPriority::Background => BACKGROUND_OOM_SCORE_ADJ,
+        VmPriority::Idle => IDLE_OOM_SCORE_ADJ,
+    };
+    let path = format!("/proc/{}/oom_score_adj", pid);
+    fs::write(&path, oom_score_adj.to_string())
+        .with_context(|| format!("Failed to write {} to {}", oom_score_adj, path))
+}
+
+/// Return the peak resident set size of the process with the given PID, in bytes, or `None` if it
+/// can't be read.
+fn peak_rss_bytes(pid: u32) -> Option<u64> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).ok()?;
+    let kib = status.lines().find_map(|line| line.strip_prefix("VmHWM:"))?;
+    let kib = kib.trim().strip_suffix("kB")?.trim().parse::<u64>().ok()?;
+    Some(kib * 1024)
+}
+
+/// Return the CPU time used by the process with the given PID in microseconds, summed over user and
+/// system time.
+fn process_cpu_time_us(pid: u32) -> Result<u64, Error> {
+    let path = format!("/proc/{}/stat", pid);
+    let stat = fs::read_to_string(&path).with_context(|| format!("Failed to read {}", path))?;
+    // The fields after the command name, which is in parentheses and may contain spaces, start with
+    // the state, so utime and stime are the 12th and 13th.
+    let fields = stat.rsplit(')').next().unwrap_or_default().split_whitespace();
+    let ticks = fields
+        .skip(11)
+        .take(2)
+        .map(|field| field.parse::<u64>())
+        .sum::<Result<u64, _>>()
+        .with_context(|| format!("Invalid {} {:?}", path, stat))?;
+    // Safe because sysconf has no side effects.
+    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks_per_sec <= 0 {
+        bail!("Invalid clock tick rate {}", ticks_per_sec);
+    }
+    Ok(ticks * 1_000_000 / ticks_per_sec as u64)
+}
+
+/// Arrange for the given file to be inherited by the child process run by `command`, returning the
+/// fd number by which the child can refer to it. The file must be kept open until the child has been
+/// spawned.
+fn preserve_fd(command: &mut Command, file: &File) -> i32 {
+    let fd = file.as_raw_fd();
+    // Safe because fcntl is async-signal-safe, and this only clears the close-on-exec flag of the
+    // fd in the child.
+    unsafe {
+        command.pre_exec(move || {
+            if libc::fcntl(fd, libc::F_SETFD, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    fd
+}
+
+/// Wait for the given child process to exit, killing it if it takes longer than the given timeout.
+fn wait_with_timeout(child: &mut Child, timeout: Duration) -> Result<ExitStatus, Error> {
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if start.elapsed() >= timeout {
+            child.kill()?;
+            child.wait()?;
+            bail!("Timed out after {:?}", timeout);
+        }
+        thread::sleep(CONTROL_POLL_INTERVAL);
+    }
+}
+
+/// Make the process run by the given command join the cgroup with the given `cgroup.procs` file
+/// before it execs, so that everything it does is charged to the cgroup. Failure to join is left
+/// for the caller to detect, as the process can't report it.
+fn join_cgroup_on_exec(command: &mut Command, procs: File) {
+    // Safe because write is async-signal-safe, and this only writes to the cgroup.procs file, which
+    // moves the child into the cgroup.
+    unsafe {
+        command.pre_exec(move || {
+            libc::write(procs.as_raw_fd(), b"0".as_ptr() as *const libc::c_void, 1);
+            Ok(())
+        });
+    }
+}
+
+/// Return a command to run crosvm, which doesn't inherit the capabilities of the Virt Manager.
+/// crosvm runs without a sandbox, so it mustn't be able to create network namespaces or raise the
+/// priority of arbitrary threads.
+fn crosvm_command() -> Command {
+    let mut command = Command::new(CROSVM_PATH);
+    // Safe because prctl is async-signal-safe, and this only clears the ambient capability set of
+    // the child. Its permitted and effective sets are then emptied when it execs crosvm.
+    unsafe {
+        command.pre_exec(|| {
+            if libc::prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL, 0, 0, 0) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    command
+}
+
+/// Return the ID of the USB device with the given usbfs file, in the `BUS:ADDR:VID:PID` form used by
+/// `crosvm usb attach`. The bus and address are not known from the file, so are given as 0.
+fn usb_device_id(device: &File) -> Result<String, Error> {
+    // The first bytes read from a usbfs file are the USB device descriptor, in which the vendor and
+    // product IDs are little-endian 16-bit values at offsets 8 and 10.
+    let mut descriptor = [0; 12];
+    device.read_exact_at(&mut descriptor, 0).context("Failed to read USB device descriptor")?;
+    let vendor_id = u16::from_le_bytes([descriptor[8], descriptor[9]]);
+    let product_id = u16::from_le_bytes([descriptor[10], descriptor[11]]);
+    Ok(format!("00:00:{:04x}:{:04x}", vendor_id, product_id))
+}
+
+/// Return the size in bytes of the disk stored in the given image, as seen by the guest. This is
+/// read from the header of qcow2 images, and is the file size of raw images.
+fn disk_size(image: &Path) -> Result<u64, Error> {
+    let file =
+        File::open(image).with_context(|| format!("Failed to open disk image {:?}", image))?;
+    let mut header = [0; 32];
+    // Images shorter than a qcow2 header can only be raw.
+    if file.read_exact_at(&mut header, 0).is_ok() {
+        if let Some(size) = qcow2_disk_size(&header) {
+            return Ok(size);
+        }
+    }
+    Ok(file.metadata()?.len())
+}
+
+/// Return the virtual disk size from the given qcow2 header, or `None` if it isn't one. The size is
+/// a big-endian 64-bit value at offset 24.
+fn qcow2_disk_size(header: &[u8; 32]) -> Option<u64> {
+    if header[..4] != QCOW2_MAGIC {
+        return None;
+    }
+    let mut size = [0; 8];
+    size.copy_from_slice(&header[24..]);
+    Some(u64::from_be_bytes(size))
+}
+
+/// Check that the directory holding the control sockets and other state of crosvm instances
+/// exists. The directories below it are created on demand.
+pub fn check_state_dir() -> Result<(), Error> {
+    let metadata = fs::metadata(CROSVM_CONTROL_SOCKET_DIR)
+        .with_context(|| format!("Failed to find {}", CROSVM_CONTROL_SOCKET_DIR))?;
+    if !metadata.is_dir() {
+        bail!("{} is not a directory", CROSVM_CONTROL_SOCKET_DIR);
+    }
+    Ok(())
+}
+
+/// Return the path of the control socket to use for the crosvm instance running the VM with the
+/// given CID.
+fn control_socket_path(cid: Cid) -> PathBuf {
+    Path::new(CROSVM_CONTROL_SOCKET_DIR).join(format!("crosvm-{}.sock", cid))
+}
+
+/// Create an empty directory for the vmm-swap file of the crosvm instance running the VM with the
+/// given CID, removing any left behind by a previous instance.
+fn create_swap_dir(cid: Cid) -> Result<PathBuf, Error> {
+    let swap_dir = Path::new(CROSVM_SWAP_DIR).join(format!("crosvm-{}", cid));
+    if swap_dir.exists() {
+        fs::remove_dir_all(&swap_dir)
+            .with_context(|| format!("Failed to remove stale swap directory {:?}", swap_dir))?;
+    }
+    fs::create_dir_all(&swap_dir)
+        .with_context(|| format!("Failed to create swap directory {:?}", swap_dir))?;
+    Ok(swap_dir)
+}
+
+/// Return whether transparent hugepages can be requested for the guest memory, i.e. whether they are
+/// enabled on the host in either `always` or `madvise` mode.
+fn transparent_hugepages_available() -> bool {
+    match fs::read_to_string(TRANSPARENT_HUGEPAGE_ENABLED_PATH) {
+        Ok(mode) => !mode.contains("[never]"),
+        Err(e) => {
+            warn!("Failed to read {}: {}", TRANSPARENT_HUGEPAGE_ENABLED_PATH, e);
+            false
+        }
+    }
+}
+
+/// Work out why the VM died, from the result of waiting for crosvm and whether Virt Manager killed
+/// it.
+fn death_reason(result: &io::Result<ExitStatus>, killed: bool) -> DeathReason {
+    match result {
+        Err(_) => DeathReason::INFRASTRUCTURE_ERROR,
+        Ok(_) if killed => DeathReason::KILLED,
+        Ok(status) => match status.code() {
+            Some(0) => DeathReason::SHUTDOWN,
+            Some(CROSVM_REBOOT_STATUS) => DeathReason::REBOOT,
+            Some(CROSVM_CRASH_STATUS) => DeathReason::CRASH,
+            Some(CROSVM_GUEST_PANIC_STATUS) => DeathReason::GUEST_PANIC,
+            _ => DeathReason::UNKNOWN,
+        },
+    }
+}
+
+/// Find the message of the most recent kernel panic in the given console output, if any.
+fn find_panic_message(console: &str) -> Option<String> {
+    console.lines().rev().find_map(|line| {
+        let start = line.find(KERNEL_PANIC_PREFIX)? + KERNEL_PANIC_PREFIX.len();
+        Some(line[start..].trim_end().to_owned())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exited(code: i32) -> io::Result<ExitStatus> {
+        Ok(ExitStatus::from_raw(code << 8))
+    }
+
+    #[test]
+    fn test_death_reason() {
+        assert_eq!(death_reason(&exited(0), false), DeathReason::SHUTDOWN);
+        assert_eq!(death_reason(&exited(CROSVM_REBOOT_STATUS), false), DeathReason::REBOOT);
+        assert_eq!(death_reason(&exited(CROSVM_CRASH_STATUS), false), DeathReason::CRASH);
+        assert_eq!(
+            death_reason(&exited(CROSVM_GUEST_PANIC_STATUS), false),
+            DeathReason::GUEST_PANIC
+        );
+        assert_eq!(death_reason(&exited(1), false), DeathReason::UNKNOWN);
+        assert_eq!(
+            death_reason(&Ok(ExitStatus::from_raw(libc::SIGSEGV)), false),
+            DeathReason::UNKNOWN
+        );
+    }
+
+    #[test]
+    fn test_death_reason_killed() {
+        assert_eq!(death_reason(&exited(0), true), DeathReason::KILLED);
+        assert_eq!(
+            death_reason(&Ok(ExitStatus::from_raw(libc::SIGKILL)), true),
+            DeathReason::KILLED
+        );
+    }
+
+    #[test]
+    fn test_death_reason_error() {
+        let error = Err(io::Error::from_raw_os_error(libc::ECHILD));
+        assert_eq!(death_reason(&error, false), DeathReason::INFRASTRUCTURE_ERROR);
+        assert_eq!(death_reason(&error, true), DeathReason::INFRASTRUCTURE_ERROR);
+    }
+
+    #[test]
+    fn test_find_panic_message() {
+        let console = "[    1.000000] Booting\n\
+            [    2.000000] Kernel panic - not syncing: VFS: Unable to mount root fs\r\n\
+            [    2.000001] CPU: 0 PID: 1 Comm: swapper\n\
+            [    3.000000] Kernel panic - not syncing: Attempted to kill init!\n\
+            [    3.000001] ---[ end Kernel panic ]---\n";
+        assert_eq!(find_panic_message(console).as_deref(), Some("Attempted to kill init!"));
+    }
+
+    #[test]
+    fn test_find_panic_message_none() {
+        assert_eq!(find_panic_message(""), None);
+        assert_eq!(find_panic_message("[    1.000000] Booting\n---[ end Kernel panic ]---"), None);
+    }
+
+    #[test]
+    fn test_qcow2_disk_size() {
+        let mut header = [0; 32];
+        header[..4].copy_from_slice(&QCOW2_MAGIC);
+        header[24..].copy_from_slice(&(3u64 << 30).to_be_bytes());
+        assert_eq!(qcow2_disk_size(&header), Some(3 << 30));
+    }
+
+    #[test]
+    fn test_qcow2_disk_size_raw() {
+        assert_eq!(qcow2_disk_size(&[0xff; 32]), None);
+    }
 }
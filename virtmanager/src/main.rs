@@ -15,13 +15,19 @@
 //! Android Virt Manager
 
 mod aidl;
+mod cgroup;
 mod config;
+mod console;
 mod crosvm;
+mod net;
+mod sched;
+mod snapshot;
 
 use crate::aidl::{VirtManager, BINDER_SERVICE_IDENTIFIER};
+use crate::crosvm::check_state_dir;
 use android_system_virtmanager::aidl::android::system::virtmanager::IVirtManager::BnVirtManager;
 use android_system_virtmanager::binder::{add_service, BinderFeatures, ProcessState};
-use log::{info, Level};
+use log::{error, info, warn, Level};
 
 /// The first CID to assign to a guest VM managed by the Virt Manager. CIDs lower than this are
 /// reserved for the host or other usage.
@@ -37,6 +43,18 @@ fn main() {
         android_logger::Config::default().with_tag(LOG_TAG).with_min_level(Level::Trace),
     );
 
+    if let Err(e) = check_state_dir() {
+        // Every VM needs a control socket in it, so none could be started.
+        error!("{:?}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = cgroup::init() {
+        warn!("Failed to set up cgroups, so VM resources won't be accounted: {:?}", e);
+    }
+    if let Err(e) = cgroup::init_cpuset() {
+        warn!("Failed to set up cpusets, so CPU affinity will only apply to vCPUs: {:?}", e);
+    }
+
     let virt_manager = VirtManager::default();
     let virt_manager = BnVirtManager::new_binder(
         virt_manager,
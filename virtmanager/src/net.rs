@@ -0,0 +1,129 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Creation of TAP network devices for VMs, each in a network namespace of its own.
+
+use crate::Cid;
+use anyhow::{anyhow, bail, Context, Error};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::thread;
+
+const TUN_DEVICE_PATH: &str = "/dev/net/tun";
+const THREAD_NETNS_PATH: &str = "/proc/thread-self/ns/net";
+
+// Constants from linux/if_tun.h, which are not all available from libc.
+const TUNSETIFF: libc::c_ulong = 0x400454ca;
+const IFF_TAP: libc::c_short = 0x0002;
+const IFF_NO_PI: libc::c_short = 0x1000;
+const IFF_VNET_HDR: libc::c_short = 0x4000;
+
+/// The `struct ifreq` used by network device ioctls, with only the flags member of the union.
+#[repr(C)]
+struct IfReq {
+    name: [u8; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    _padding: [u8; 22],
+}
+
+impl IfReq {
+    fn new(name: &str) -> Result<IfReq, Error> {
+        let mut ifreq = IfReq { name: [0; libc::IFNAMSIZ], flags: 0, _padding: [0; 22] };
+        // Leave room for the NUL terminator.
+        if name.len() >= ifreq.name.len() {
+            bail!("Interface name {:?} is too long", name);
+        }
+        ifreq.name[..name.len()].copy_from_slice(name.as_bytes());
+        Ok(ifreq)
+    }
+}
+
+/// A TAP device for a VM, in a network namespace of its own.
+#[derive(Debug)]
+pub struct TapDevice {
+    /// The TAP device, to be passed to crosvm.
+    pub file: File,
+    /// The name of the host side interface of the TAP device, within its network namespace.
+    pub name: String,
+    /// The network namespace containing the interface. Holding this keeps the namespace alive.
+    pub netns: File,
+}
+
+impl TapDevice {
+    /// Create a TAP device for the VM with the given CID in a new network namespace, and bring its
+    /// interface up.
+    pub fn create(cid: Cid) -> Result<TapDevice, Error> {
+        let name = format!("crosvm_tap{}", cid);
+        // Changing network namespace only affects the calling thread, so use a new thread to avoid
+        // moving any other Virt Manager threads into the new namespace.
+        thread::spawn(move || create_in_new_netns(name))
+            .join()
+            .map_err(|_| anyhow!("Thread creating TAP device panicked"))?
+    }
+}
+
+/// Move the current thread into a new network namespace, and create a TAP device with the given
+/// name there.
+fn create_in_new_netns(name: String) -> Result<TapDevice, Error> {
+    // Safe because this only changes the network namespace of the current thread, which is only
+    // used to create the TAP device.
+    if unsafe { libc::unshare(libc::CLONE_NEWNET) } < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to create network namespace");
+    }
+    let netns = File::open(THREAD_NETNS_PATH)
+        .with_context(|| format!("Failed to open {}", THREAD_NETNS_PATH))?;
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(TUN_DEVICE_PATH)
+        .with_context(|| format!("Failed to open {}", TUN_DEVICE_PATH))?;
+    let mut ifreq = IfReq::new(&name)?;
+    ifreq.flags = IFF_TAP | IFF_NO_PI | IFF_VNET_HDR;
+    // Safe because we pass a valid ifreq, which the kernel only reads and writes within, and we
+    // check for an error.
+    if unsafe { libc::ioctl(file.as_raw_fd(), TUNSETIFF as _, &mut ifreq) } < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to create TAP device");
+    }
+
+    set_interface_up(&name)?;
+    Ok(TapDevice { file, name, netns })
+}
+
+/// Bring up the network interface with the given name, in the network namespace of the current
+/// thread.
+fn set_interface_up(name: &str) -> Result<(), Error> {
+    // Safe because this just creates a new socket, and we check for an error.
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to create socket");
+    }
+    // Safe because we have just created the socket so we own it, and `from_raw_fd` takes ownership
+    // of it.
+    let socket = unsafe { File::from_raw_fd(fd) };
+
+    let mut ifreq = IfReq::new(name)?;
+    // Safe because we pass a valid ifreq, which the kernel only reads and writes within, and we
+    // check for an error.
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCGIFFLAGS as _, &mut ifreq) } < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to get interface flags");
+    }
+    ifreq.flags |= libc::IFF_UP as libc::c_short;
+    // Safe because we pass a valid ifreq, which the kernel only reads, and we check for an error.
+    if unsafe { libc::ioctl(socket.as_raw_fd(), libc::SIOCSIFFLAGS as _, &ifreq) } < 0 {
+        return Err(io::Error::last_os_error()).context("Failed to bring up interface");
+    }
+    Ok(())
+}
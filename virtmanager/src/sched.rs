@@ -0,0 +1,133 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Scheduling settings for the vCPU threads of crosvm.
+
+use crate::config::SchedulerPriority;
+use log::{error, info};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::mem::size_of;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The prefix of the names crosvm gives to its vCPU threads, followed by the vCPU index.
+const VCPU_THREAD_NAME_PREFIX: &str = "crosvm_vcpu";
+
+/// How long after starting crosvm to keep looking for new vCPU threads.
+const VCPU_SEARCH_DURATION: Duration = Duration::from_secs(10);
+
+/// How often to look for new vCPU threads.
+const VCPU_SEARCH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The real-time priority given to vCPU threads. This is the lowest, so that vCPUs can't starve
+/// any real-time threads of the host.
+const VCPU_RT_PRIORITY: u32 = 1;
+
+/// The minimum utilization clamp given to boosted vCPU threads, out of 1024.
+const BOOSTED_UTIL_MIN: u32 = 512;
+
+/// The minimum utilization clamp given to real-time vCPU threads, out of 1024.
+const RT_UTIL_MIN: u32 = 1024;
+
+// Constants from linux/sched.h, which are not available from libc.
+const SCHED_FLAG_UTIL_CLAMP_MIN: u64 = 0x20;
+
+/// The `struct sched_attr` used by the `sched_setattr` syscall.
+#[repr(C)]
+#[derive(Default)]
+struct SchedAttr {
+    size: u32,
+    sched_policy: u32,
+    sched_flags: u64,
+    sched_nice: i32,
+    sched_priority: u32,
+    sched_runtime: u64,
+    sched_deadline: u64,
+    sched_period: u64,
+    sched_util_min: u32,
+    sched_util_max: u32,
+}
+
+impl SchedAttr {
+    fn new(priority: SchedulerPriority) -> SchedAttr {
+        let (sched_policy, sched_priority, sched_util_min) = match priority {
+            SchedulerPriority::Boosted => (libc::SCHED_OTHER, 0, BOOSTED_UTIL_MIN),
+            SchedulerPriority::RealTime => (libc::SCHED_FIFO, VCPU_RT_PRIORITY, RT_UTIL_MIN),
+        };
+        SchedAttr {
+            size: size_of::<SchedAttr>() as u32,
+            sched_policy: sched_policy as u32,
+            sched_flags: SCHED_FLAG_UTIL_CLAMP_MIN,
+            sched_priority,
+            sched_util_min,
+            ..Default::default()
+        }
+    }
+}
+
+/// Apply the given scheduler priority to the vCPU threads of the crosvm process with the given PID,
+/// as they are created. This returns immediately, and looks for the threads in the background.
+pub fn set_vcpu_priority(pid: u32, priority: SchedulerPriority) {
+    thread::spawn(move || {
+        let start = Instant::now();
+        let mut done = BTreeSet::new();
+        while start.elapsed() < VCPU_SEARCH_DURATION {
+            let tids = match vcpu_threads(pid) {
+                Ok(tids) => tids,
+                // The process has exited.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(e) => {
+                    error!("Failed to list threads of crosvm process {}: {}", pid, e);
+                    vec![]
+                }
+            };
+            for tid in tids.into_iter().filter(|tid| done.insert(*tid)) {
+                match set_thread_priority(tid, priority) {
+                    Ok(()) => info!("Set priority of vCPU thread {} to {:?}", tid, priority),
+                    Err(e) => error!("Failed to set priority of vCPU thread {}: {}", tid, e),
+                }
+            }
+            thread::sleep(VCPU_SEARCH_INTERVAL);
+        }
+    });
+}
+
+/// Return the thread IDs of the vCPU threads of the crosvm process with the given PID.
+fn vcpu_threads(pid: u32) -> io::Result<Vec<u32>> {
+    let mut tids = vec![];
+    for entry in fs::read_dir(format!("/proc/{}/task", pid))? {
+        let entry = entry?;
+        // The thread may exit at any time, so ignore it if its name can't be read.
+        if let Ok(name) = fs::read_to_string(entry.path().join("comm")) {
+            if name.starts_with(VCPU_THREAD_NAME_PREFIX) {
+                if let Some(tid) = entry.file_name().to_str().and_then(|tid| tid.parse().ok()) {
+                    tids.push(tid);
+                }
+            }
+        }
+    }
+    Ok(tids)
+}
+
+fn set_thread_priority(tid: u32, priority: SchedulerPriority) -> io::Result<()> {
+    let attr = SchedAttr::new(priority);
+    // Safe because we pass a valid sched_attr of the size given in it, which the kernel only reads,
+    // and we check for an error.
+    if unsafe { libc::syscall(libc::SYS_sched_setattr, tid, &attr, 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
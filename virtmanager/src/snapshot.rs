@@ -0,0 +1,103 @@
+// Copyright 2021, The Android Open Source Project
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Storage of VM snapshots taken with `crosvm snapshot`.
+
+use crate::config::VmConfig;
+use crate::Cid;
+use anyhow::{bail, Context, Error};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+/// The directory in which snapshots are stored, with a subdirectory for each UID which has taken
+/// snapshots.
+const SNAPSHOT_DIR: &str = "/data/misc/virtmanager/snapshots";
+
+/// The name of the file within a snapshot directory holding the config of the VM.
+const CONFIG_FILENAME: &str = "config.json";
+
+/// The name of the file within a snapshot directory holding the CID of the VM.
+const CID_FILENAME: &str = "cid";
+
+/// The name of the file within a snapshot directory holding the state saved by crosvm.
+const STATE_FILENAME: &str = "crosvm_state";
+
+/// A named snapshot of a VM, belonging to a particular UID.
+#[derive(Debug)]
+pub struct Snapshot {
+    /// The directory in which the snapshot is stored.
+    dir: PathBuf,
+}
+
+impl Snapshot {
+    /// Get the snapshot with the given name belonging to the given UID, which may or may not exist
+    /// yet. Names may only contain ASCII letters, digits, '-', '_' and '.', and may not start with
+    /// '.'.
+    pub fn new(owner_uid: u32, name: &str) -> Result<Snapshot, Error> {
+        let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+        if name.is_empty() || name.starts_with('.') || !name.chars().all(valid_char) {
+            bail!("Invalid snapshot name {:?}", name);
+        }
+        Ok(Snapshot { dir: Path::new(SNAPSHOT_DIR).join(owner_uid.to_string()).join(name) })
+    }
+
+    /// The path of the file in which crosvm saves the state of the VM.
+    pub fn state_path(&self) -> PathBuf {
+        self.dir.join(STATE_FILENAME)
+    }
+
+    /// Create the directory for the snapshot, replacing any previous snapshot with the same name,
+    /// and save the config and CID of the VM to it.
+    pub fn create(&self, config: &VmConfig, cid: Cid) -> Result<(), Error> {
+        if self.dir.exists() {
+            fs::remove_dir_all(&self.dir)
+                .with_context(|| format!("Failed to remove old snapshot {:?}", self.dir))?;
+        }
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Failed to create snapshot directory {:?}", self.dir))?;
+        let config_file = File::create(self.dir.join(CONFIG_FILENAME))?;
+        serde_json::to_writer(config_file, config)?;
+        fs::write(self.dir.join(CID_FILENAME), cid.to_string())?;
+        Ok(())
+    }
+
+    /// Load the CID of the VM from which the snapshot was taken.
+    pub fn load_cid(&self) -> Result<Cid, Error> {
+        let cid = fs::read_to_string(self.dir.join(CID_FILENAME))
+            .with_context(|| format!("Failed to read CID of snapshot {:?}", self.dir))?;
+        cid.trim().parse().with_context(|| format!("Invalid CID {:?} in snapshot", cid))
+    }
+
+    /// Load the config of the VM from which the snapshot was taken, and check that it is still
+    /// valid and that all the files it refers to still exist.
+    pub fn load_config(&self) -> Result<VmConfig, Error> {
+        if !self.state_path().exists() {
+            bail!("Snapshot {:?} does not exist", self.dir);
+        }
+        let config = VmConfig::load(&File::open(self.dir.join(CONFIG_FILENAME))?)?;
+        config.validate()?;
+        let files = config
+            .kernel
+            .iter()
+            .chain(&config.initrd)
+            .chain(&config.bootloader)
+            .chain(config.disks.iter().chain(&config.pmem_disks).map(|disk| &disk.image));
+        for file in files {
+            if !Path::new(file).exists() {
+                bail!("{:?} used by snapshot {:?} no longer exists", file, self.dir);
+            }
+        }
+        Ok(config)
+    }
+}
@@ -18,8 +18,9 @@ mod run;
 mod sync;
 
 use android_system_virtmanager::aidl::android::system::virtmanager::IVirtManager::IVirtManager;
+use android_system_virtmanager::aidl::android::system::virtmanager::VirtualMachineLabel::VirtualMachineLabel;
 use android_system_virtmanager::binder::{get_interface, ProcessState, Strong};
-use anyhow::{Context, Error};
+use anyhow::{anyhow, Context, Error};
 use run::command_run;
 use std::path::PathBuf;
 use structopt::clap::AppSettings;
@@ -46,7 +47,11 @@ enum Opt {
         cid: u32,
     },
     /// List running virtual machines
-    List,
+    List {
+        /// Only list virtual machines with the given label, in the form KEY=VALUE
+        #[structopt(long = "label", parse(try_from_str = parse_label))]
+        labels: Vec<VirtualMachineLabel>,
+    },
 }
 
 fn main() -> Result<(), Error> {
@@ -62,7 +67,7 @@ fn main() -> Result<(), Error> {
     match opt {
         Opt::Run { config, daemonize } => command_run(virt_manager, &config, daemonize),
         Opt::Stop { cid } => command_stop(virt_manager, cid),
-        Opt::List => command_list(virt_manager),
+        Opt::List { labels } => command_list(virt_manager, &labels),
     }
 }
 
@@ -75,9 +80,23 @@ fn command_stop(virt_manager: Strong<dyn IVirtManager>, cid: u32) -> Result<(),
     Ok(())
 }
 
-/// List the VMs currently running.
-fn command_list(virt_manager: Strong<dyn IVirtManager>) -> Result<(), Error> {
-    let vms = virt_manager.debugListVms().context("Failed to get list of VMs")?;
+/// List the VMs currently running which have all of the given labels.
+fn command_list(
+    virt_manager: Strong<dyn IVirtManager>,
+    labels: &[VirtualMachineLabel],
+) -> Result<(), Error> {
+    let vms = virt_manager.debugListVms(labels).context("Failed to get list of VMs")?;
     println!("Running VMs: {:#?}", vms);
     Ok(())
 }
+
+/// Parse a label given on the command line in the form `KEY=VALUE`.
+fn parse_label(s: &str) -> Result<VirtualMachineLabel, Error> {
+    let mut parts = s.splitn(2, '=');
+    match (parts.next(), parts.next()) {
+        (Some(key), Some(value)) => {
+            Ok(VirtualMachineLabel { key: key.to_owned(), value: value.to_owned() })
+        }
+        _ => Err(anyhow!("Label must be in the form KEY=VALUE")),
+    }
+}
@@ -15,11 +15,14 @@
 //! Command to run a VM.
 
 use crate::sync::AtomicFlag;
+use android_system_virtmanager::aidl::android::system::virtmanager::CrashReport::CrashReport;
+use android_system_virtmanager::aidl::android::system::virtmanager::DeathReason::DeathReason;
 use android_system_virtmanager::aidl::android::system::virtmanager::IVirtManager::IVirtManager;
 use android_system_virtmanager::aidl::android::system::virtmanager::IVirtualMachine::IVirtualMachine;
 use android_system_virtmanager::aidl::android::system::virtmanager::IVirtualMachineCallback::{
     BnVirtualMachineCallback, IVirtualMachineCallback,
 };
+use android_system_virtmanager::aidl::android::system::virtmanager::ResourceUsage::ResourceUsage;
 use android_system_virtmanager::binder::{
     BinderFeatures, DeathRecipient, IBinder, ParcelFileDescriptor, Strong,
 };
@@ -46,6 +49,9 @@ pub fn command_run(
 
     let cid = vm.getCid().context("Failed to get CID")?;
     println!("Started VM from {} with CID {}.", config_filename, cid);
+    if let Some(interface) = vm.getTapInterfaceName().context("Failed to get TAP interface")? {
+        println!("VM network is on TAP interface {} in its own network namespace.", interface);
+    }
 
     if daemonize {
         // Pass the VM reference back to Virt Manager and have it hold it in the background.
@@ -93,11 +99,35 @@ struct VirtualMachineCallback {
 impl Interface for VirtualMachineCallback {}
 
 impl IVirtualMachineCallback for VirtualMachineCallback {
-    fn onDied(&self, _cid: i32) -> BinderResult<()> {
-        println!("VM died");
+    fn onDied(
+        &self,
+        _cid: i32,
+        reason: DeathReason,
+        usage: Option<&ResourceUsage>,
+    ) -> BinderResult<()> {
+        println!("VM died: {:?}", reason);
+        if let Some(usage) = usage {
+            println!(
+                "CPU time {} us, peak memory {} bytes, I/O read {} bytes, written {} bytes",
+                usage.cpuTimeMicros, usage.peakMemoryBytes, usage.ioReadBytes, usage.ioWriteBytes
+            );
+        }
         self.dead.raise();
         Ok(())
     }
+
+    fn onGuestPanic(&self, _cid: i32, message: Option<&str>) -> BinderResult<()> {
+        println!("VM kernel panicked: {}", message.unwrap_or("<no message found>"));
+        Ok(())
+    }
+
+    fn onCrash(&self, _cid: i32, report: &CrashReport) -> BinderResult<()> {
+        println!(
+            "crosvm crashed with exit status {}, signal {}. crosvm output:\n{}",
+            report.exitStatus, report.signal, report.crosvmStderr
+        );
+        Ok(())
+    }
 }
 
 /// Safely duplicate the standard output file descriptor.